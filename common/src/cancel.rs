@@ -0,0 +1,176 @@
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Instant;
+
+use tokio::sync::Notify;
+
+/// [`Context`](crate::context::Context)被取消的原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CancelReason {
+    /// 透過[`CancelHandle::cancel`]主動取消
+    Cancelled,
+    /// 超過了設定的deadline
+    DeadlineExceeded,
+}
+
+impl std::fmt::Display for CancelReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CancelReason::Cancelled => f.write_str("context cancelled"),
+            CancelReason::DeadlineExceeded => f.write_str("context deadline exceeded"),
+        }
+    }
+}
+
+impl std::error::Error for CancelReason {}
+
+/// 用來取消由`Context::with_cancel`等方法產生的子[`Context`](crate::context::Context)
+#[derive(Clone)]
+pub struct CancelHandle {
+    node: Arc<CancelNode>,
+}
+
+impl CancelHandle {
+    pub(crate) fn new(node: Arc<CancelNode>) -> Self {
+        CancelHandle { node }
+    }
+
+    /// 取消對應的Context以及所有由它衍生的子Context
+    pub fn cancel(&self) {
+        self.node.cancel();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.node.reason().is_some()
+    }
+}
+
+impl std::fmt::Debug for CancelHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CancelHandle")
+            .field("deadline", &self.node.deadline)
+            .field("cancelled", &self.is_cancelled())
+            .finish()
+    }
+}
+
+const NOT_CANCELLED: u8 = 0;
+const CANCELLED: u8 = 1;
+const DEADLINE_EXCEEDED: u8 = 2;
+
+/// A node of the cancellation tree. Cancelling a node cancels all of its
+/// descendants, while the deadline of a node is already the earliest deadline
+/// of its ancestors, so no timer is needed to propagate it.
+pub(crate) struct CancelNode {
+    cancelled: AtomicBool,
+    /// The first reason observed, kept once set
+    reason: AtomicU8,
+    notify: Notify,
+    deadline: Option<Instant>,
+    children: Mutex<Vec<Weak<CancelNode>>>,
+}
+
+impl CancelNode {
+    pub(crate) fn child(parent: Option<&Arc<CancelNode>>, deadline: Option<Instant>) -> Arc<Self> {
+        let deadline = match (parent.and_then(|p| p.deadline), deadline) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+
+        let node = Arc::new(CancelNode {
+            cancelled: AtomicBool::new(false),
+            reason: AtomicU8::new(NOT_CANCELLED),
+            notify: Notify::new(),
+            deadline,
+            children: Mutex::new(Vec::new()),
+        });
+
+        if let Some(parent) = parent {
+            // Check the flag while holding the lock, so a concurrent `cancel` either
+            // sees this child in the list or we see the flag it has set.
+            let mut children = parent.children.lock().unwrap();
+            if parent.cancelled.load(Ordering::SeqCst) {
+                node.cancel();
+            } else {
+                children.retain(|child| child.strong_count() > 0);
+                children.push(Arc::downgrade(&node));
+            }
+        }
+
+        node
+    }
+
+    pub(crate) fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    pub(crate) fn cancel(&self) {
+        if self.deadline_passed() {
+            self.record(CancelReason::DeadlineExceeded);
+        } else {
+            self.record(CancelReason::Cancelled);
+        }
+        if self.cancelled.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        self.notify.notify_waiters();
+
+        let children = std::mem::take(&mut *self.children.lock().unwrap());
+        for child in children.iter().filter_map(Weak::upgrade) {
+            child.cancel();
+        }
+    }
+
+    pub(crate) fn reason(&self) -> Option<CancelReason> {
+        match self.reason.load(Ordering::SeqCst) {
+            CANCELLED => Some(CancelReason::Cancelled),
+            DEADLINE_EXCEEDED => Some(CancelReason::DeadlineExceeded),
+            _ if self.deadline_passed() => Some(self.record(CancelReason::DeadlineExceeded)),
+            _ => None,
+        }
+    }
+
+    fn deadline_passed(&self) -> bool {
+        self.deadline
+            .is_some_and(|deadline| deadline <= Instant::now())
+    }
+
+    /// Records `reason` unless another one was recorded first, and returns the kept one
+    fn record(&self, reason: CancelReason) -> CancelReason {
+        let value = match reason {
+            CancelReason::Cancelled => CANCELLED,
+            CancelReason::DeadlineExceeded => DEADLINE_EXCEEDED,
+        };
+        match self
+            .reason
+            .compare_exchange(NOT_CANCELLED, value, Ordering::SeqCst, Ordering::SeqCst)
+        {
+            Ok(_) => reason,
+            Err(CANCELLED) => CancelReason::Cancelled,
+            Err(_) => CancelReason::DeadlineExceeded,
+        }
+    }
+
+    pub(crate) async fn cancelled(self: Arc<Self>) {
+        let flagged = async {
+            loop {
+                let notified = self.notify.notified();
+                tokio::pin!(notified);
+                // Register before checking the flag so a wakeup can not be missed
+                notified.as_mut().enable();
+                if self.cancelled.load(Ordering::SeqCst) {
+                    return;
+                }
+                notified.await;
+            }
+        };
+
+        match self.deadline {
+            Some(deadline) => tokio::select! {
+                _ = flagged => {}
+                _ = tokio::time::sleep_until(deadline.into()) => {}
+            },
+            None => flagged.await,
+        }
+    }
+}
@@ -0,0 +1,310 @@
+use std::future::{ready, Ready};
+use std::sync::Arc;
+use std::task::{Context as TaskContext, Poll};
+
+use futures::future::Either;
+use tonic::metadata::MetadataMap;
+use tonic::service::Interceptor;
+use tower::Service;
+
+use crate::cancel::CancelReason;
+use crate::context::Context;
+use crate::propagation::{self, Propagator};
+
+/// request id, tenant id, auth token, deadline, trace context與baggage
+fn default_propagators() -> Vec<Arc<dyn Propagator>> {
+    vec![
+        Arc::new(propagation::request_id()),
+        Arc::new(propagation::tenant_id()),
+        Arc::new(propagation::auth_token()),
+        Arc::new(propagation::GrpcTimeoutPropagator),
+        Arc::new(propagation::TraceContextPropagator),
+        Arc::new(propagation::BaggagePropagator),
+    ]
+}
+
+fn inject_current(propagators: &[Arc<dyn Propagator>], metadata: &mut MetadataMap) {
+    Context::map_current(|cx| {
+        for propagator in propagators {
+            propagator.inject(cx, metadata);
+        }
+    })
+}
+
+/// A request of a cancelled context is not sent, its deadline may already be
+/// past and a zero timeout would not tell the server anything useful
+fn cancelled_status() -> Option<tonic::Status> {
+    let reason = Context::map_current(Context::cancel_reason)?;
+    Some(match reason {
+        CancelReason::DeadlineExceeded => tonic::Status::deadline_exceeded(reason.to_string()),
+        CancelReason::Cancelled => tonic::Status::cancelled(reason.to_string()),
+    })
+}
+
+/// 在發出請求時把[`Context::current`]寫入metadata的`tonic::service::Interceptor`
+///
+/// Context已被取消或超過deadline時, 請求不會被送出
+#[derive(Clone)]
+pub struct ContextInterceptor {
+    propagators: Vec<Arc<dyn Propagator>>,
+}
+
+impl ContextInterceptor {
+    /// 使用預設的[`Propagator`]
+    pub fn new() -> Self {
+        ContextInterceptor {
+            propagators: default_propagators(),
+        }
+    }
+
+    /// 不含任何[`Propagator`], 需自行以`with_propagator`註冊
+    pub fn empty() -> Self {
+        ContextInterceptor {
+            propagators: Vec::new(),
+        }
+    }
+
+    pub fn with_propagator(mut self, propagator: impl Propagator) -> Self {
+        self.propagators.push(Arc::new(propagator));
+        self
+    }
+}
+
+impl Default for ContextInterceptor {
+    fn default() -> Self {
+        ContextInterceptor::new()
+    }
+}
+
+impl std::fmt::Debug for ContextInterceptor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ContextInterceptor")
+            .field("propagators", &self.propagators.len())
+            .finish()
+    }
+}
+
+impl Interceptor for ContextInterceptor {
+    fn call(
+        &mut self,
+        mut request: tonic::Request<()>,
+    ) -> Result<tonic::Request<()>, tonic::Status> {
+        if let Some(status) = cancelled_status() {
+            return Err(status);
+        }
+
+        inject_current(&self.propagators, request.metadata_mut());
+        Ok(request)
+    }
+}
+
+/// 在發出請求時把[`Context::current`]寫入header的`tower::Layer`, 用於`tonic::transport::Channel`
+///
+/// Context已被取消或超過deadline時, 請求不會被送出, 而是回傳只含gRPC status的response
+#[derive(Clone)]
+pub struct ContextPropagationLayer {
+    propagators: Vec<Arc<dyn Propagator>>,
+}
+
+impl ContextPropagationLayer {
+    /// 使用預設的[`Propagator`]
+    pub fn new() -> Self {
+        ContextPropagationLayer {
+            propagators: default_propagators(),
+        }
+    }
+
+    /// 不含任何[`Propagator`], 需自行以`with_propagator`註冊
+    pub fn empty() -> Self {
+        ContextPropagationLayer {
+            propagators: Vec::new(),
+        }
+    }
+
+    pub fn with_propagator(mut self, propagator: impl Propagator) -> Self {
+        self.propagators.push(Arc::new(propagator));
+        self
+    }
+}
+
+impl Default for ContextPropagationLayer {
+    fn default() -> Self {
+        ContextPropagationLayer::new()
+    }
+}
+
+impl std::fmt::Debug for ContextPropagationLayer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ContextPropagationLayer")
+            .field("propagators", &self.propagators.len())
+            .finish()
+    }
+}
+
+impl<S> tower::Layer<S> for ContextPropagationLayer {
+    type Service = ContextPropagationService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ContextPropagationService {
+            inner,
+            propagators: self.propagators.clone().into(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ContextPropagationService<S> {
+    inner: S,
+    propagators: Arc<[Arc<dyn Propagator>]>,
+}
+
+impl<S: std::fmt::Debug> std::fmt::Debug for ContextPropagationService<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ContextPropagationService")
+            .field("inner", &self.inner)
+            .field("propagators", &self.propagators.len())
+            .finish()
+    }
+}
+
+impl<S, B, ResBody> Service<http::Request<B>> for ContextPropagationService<S>
+where
+    S: Service<http::Request<B>, Response = http::Response<ResBody>>,
+    ResBody: Default,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Either<S::Future, Ready<Result<S::Response, S::Error>>>;
+
+    fn poll_ready(&mut self, cx: &mut TaskContext<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: http::Request<B>) -> Self::Future {
+        if let Some(status) = cancelled_status() {
+            return Either::Right(ready(Ok(status_response(status))));
+        }
+
+        let mut metadata = MetadataMap::from_headers(std::mem::take(request.headers_mut()));
+        inject_current(&self.propagators, &mut metadata);
+        *request.headers_mut() = metadata.into_headers();
+
+        Either::Left(self.inner.call(request))
+    }
+}
+
+/// A trailers-only response, which the tonic client returns as `Err(status)`
+fn status_response<B: Default>(status: tonic::Status) -> http::Response<B> {
+    let mut response = http::Response::new(B::default());
+    response.headers_mut().insert(
+        http::header::CONTENT_TYPE,
+        http::HeaderValue::from_static("application/grpc"),
+    );
+    // The messages of `CancelReason` are plain ASCII, encoding them can not fail
+    let _ = status.add_header(response.headers_mut());
+    response
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::propagation::{AuthToken, Baggage, RequestId};
+    use std::time::Duration;
+    use tower::Layer;
+
+    fn request_context() -> Context {
+        let mut baggage = Baggage::new();
+        baggage.insert("region", "tw");
+        Context::new()
+            .with_value(RequestId("req-1".to_string()))
+            .with_value(AuthToken("token".to_string()))
+            .with_value(baggage)
+    }
+
+    #[test]
+    fn interceptor_injects_current_context() {
+        let (cx, _) = request_context().with_timeout(Duration::from_secs(5));
+        let _guard = cx.attach();
+
+        let request = ContextInterceptor::new()
+            .call(tonic::Request::new(()))
+            .unwrap();
+        let metadata = request.metadata();
+        assert_eq!(metadata.get("x-request-id").unwrap(), "req-1");
+        assert_eq!(metadata.get("authorization").unwrap(), "Bearer token");
+        assert_eq!(metadata.get("baggage").unwrap(), "region=tw");
+        assert!(metadata.get("grpc-timeout").is_some());
+        assert!(metadata.get("x-tenant-id").is_none());
+    }
+
+    #[test]
+    fn interceptor_rejects_cancelled_context() {
+        let (cx, handle) = Context::new().with_cancel();
+        handle.cancel();
+        let _guard = cx.attach();
+
+        let status = ContextInterceptor::new()
+            .call(tonic::Request::new(()))
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Cancelled);
+    }
+
+    struct Headers;
+
+    impl Service<http::Request<()>> for Headers {
+        type Response = http::Response<()>;
+        type Error = std::convert::Infallible;
+        type Future = Ready<Result<Self::Response, Self::Error>>;
+
+        fn poll_ready(&mut self, _: &mut TaskContext<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, request: http::Request<()>) -> Self::Future {
+            let mut response = http::Response::new(());
+            *response.headers_mut() = request.headers().clone();
+            ready(Ok(response))
+        }
+    }
+
+    #[tokio::test]
+    async fn layer_injects_current_context() {
+        let mut service = ContextPropagationLayer::new().layer(Headers);
+        let request = http::Request::builder()
+            .header("content-type", "application/grpc")
+            .body(())
+            .unwrap();
+
+        let response = {
+            let _guard = request_context().attach();
+            service.call(request)
+        }
+        .await
+        .unwrap();
+        let headers = response.headers();
+
+        assert_eq!(headers.get("content-type").unwrap(), "application/grpc");
+        assert_eq!(headers.get("x-request-id").unwrap(), "req-1");
+        assert_eq!(headers.get("authorization").unwrap(), "Bearer token");
+        assert!(headers.get("grpc-timeout").is_none());
+    }
+
+    #[tokio::test]
+    async fn layer_rejects_expired_context() {
+        let mut service = ContextPropagationLayer::new().layer(Headers);
+        let (cx, _) = request_context().with_timeout(Duration::ZERO);
+
+        let response = {
+            let _guard = cx.attach();
+            service.call(http::Request::new(()))
+        }
+        .await
+        .unwrap();
+
+        let status = tonic::Status::from_header_map(response.headers()).unwrap();
+        assert_eq!(status.code(), tonic::Code::DeadlineExceeded);
+        // Not sent, so nothing is injected
+        assert!(response.headers().get("x-request-id").is_none());
+        assert!(response.headers().get("grpc-timeout").is_none());
+    }
+}
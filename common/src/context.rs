@@ -1,19 +1,137 @@
 use core::cell::RefCell;
 use std::any::{Any, TypeId};
-use std::hash::{BuildHasherDefault, Hasher};
+use std::future::Future;
+use std::hash::{BuildHasherDefault, Hash, Hasher};
 use std::marker::PhantomData;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use std::collections::HashMap;
+use rpds::HashTrieMapSync;
+
+use crate::cancel::{CancelHandle, CancelNode, CancelReason};
+use crate::with_context::FutureExt;
 
 thread_local! {
     static CURRENT_CONTEXT: RefCell<Context> = RefCell::new(Context::new());
 }
 
-/// [`Context`]是一個管理線程上下文的結構體
+#[cfg(feature = "task-local")]
+tokio::task_local! {
+    static TASK_CONTEXT: RefCell<Context>;
+}
+
+/// Entries are kept in a persistent hash trie, so deriving a child context shares
+/// the structure of its parent instead of copying it.
+type Entries = HashTrieMapSync<EntryKey, Arc<dyn Any + Sync + Send>, BuildHasherDefault<IdHasher>>;
+
+/// [`Context`]是一個管理線程上下文的結構體, clone與衍生子Context都不會複製所有的值
 #[derive(Default, Clone)]
 pub struct Context {
-    entries: HashMap<TypeId, Arc<dyn Any + Sync + Send>, BuildHasherDefault<IdHasher>>,
+    entries: Entries,
+    cancel: Option<Arc<CancelNode>>,
+}
+
+/// Values stored by type have no key, values stored by [`ContextKey`] also carry its id.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct EntryKey {
+    type_id: TypeId,
+    key: Option<usize>,
+}
+
+impl EntryKey {
+    fn of<T: 'static>(key: Option<usize>) -> Self {
+        EntryKey {
+            type_id: TypeId::of::<T>(),
+            key,
+        }
+    }
+}
+
+impl Hash for EntryKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.type_id.hash(state);
+        if let Some(key) = self.key {
+            state.write(&key.to_ne_bytes());
+        }
+    }
+}
+
+/// [`ContextKey`]用來在[`Context`]中存放多個相同型別的值, 必須宣告為`static`,
+/// 宣告為`const`時每次使用都是不同的key
+///
+/// ```
+/// use common::context::{Context, ContextKey};
+///
+/// static REQUEST_ID: ContextKey<String> = ContextKey::new("request_id");
+/// static RETRY_LIMIT: ContextKey<u32> = ContextKey::with_default("retry_limit", &3);
+///
+/// let cx = Context::new().with_keyed_value(&REQUEST_ID, "abc".to_string());
+/// assert_eq!(cx.get_keyed(&REQUEST_ID).map(String::as_str), Some("abc"));
+/// assert_eq!(cx.get_keyed(&RETRY_LIMIT), Some(&3));
+/// ```
+pub struct ContextKey<T: 'static> {
+    name: &'static str,
+    default: Option<&'static T>,
+    /// Assigned on first use, 0 until then
+    id: AtomicUsize,
+}
+
+/// The id of the next [`ContextKey`] used, ids are never reused
+static NEXT_KEY_ID: AtomicUsize = AtomicUsize::new(1);
+
+impl<T: 'static> ContextKey<T> {
+    /// 每個key各自存放值, 名稱相同也不會互相覆蓋, 名稱只用於Debug與propagation
+    pub const fn new(name: &'static str) -> Self {
+        ContextKey {
+            name,
+            default: None,
+            id: AtomicUsize::new(0),
+        }
+    }
+
+    /// 當Context中沒有對應的值時, `get_keyed`會回傳`default`
+    pub const fn with_default(name: &'static str, default: &'static T) -> Self {
+        ContextKey {
+            name,
+            default: Some(default),
+            id: AtomicUsize::new(0),
+        }
+    }
+
+    pub const fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn entry_key(&self) -> EntryKey {
+        EntryKey::of::<T>(Some(self.id()))
+    }
+
+    /// A `const fn` can not take an id from the counter, so it is taken on
+    /// first use, the first thread to store it wins
+    fn id(&self) -> usize {
+        let id = self.id.load(Ordering::Relaxed);
+        if id != 0 {
+            return id;
+        }
+        let next = NEXT_KEY_ID.fetch_add(1, Ordering::Relaxed);
+        match self
+            .id
+            .compare_exchange(0, next, Ordering::Relaxed, Ordering::Relaxed)
+        {
+            Ok(_) => next,
+            Err(id) => id,
+        }
+    }
+}
+
+impl<T: 'static> std::fmt::Debug for ContextKey<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ContextKey")
+            .field("name", &self.name)
+            .field("type", &std::any::type_name::<T>())
+            .finish()
+    }
 }
 
 impl Context {
@@ -26,58 +144,217 @@ impl Context {
     }
 
     pub fn map_current<T>(f: impl FnOnce(&Context) -> T) -> T {
+        #[cfg(feature = "task-local")]
+        let f = {
+            let mut f = Some(f);
+            // Prefer the context of the current task, and fall back to the thread
+            // outside of a task scope (e.g. inside `spawn_blocking`)
+            if let Ok(value) = TASK_CONTEXT.try_with(|cx| (f.take().unwrap())(&cx.borrow())) {
+                return value;
+            }
+            f.unwrap()
+        };
+
         CURRENT_CONTEXT.with(|cx| f(&cx.borrow()))
     }
 
-    pub fn current_with_value<T: 'static + Send + Sync>(value: T) -> Self {
-        let mut new_context = Context::current();
-        new_context
-            .entries
-            .insert(TypeId::of::<T>(), Arc::new(value));
+    /// 在`self`之下執行`future`, 與`future.with_context(self)`相同
+    pub async fn scope<F: Future>(self, future: F) -> F::Output {
+        future.with_context(self).await
+    }
+
+    /// Runs `f` with `self` as the current context, and keeps any context attached
+    /// by `f` which is still in place when it returns. Used by `WithContext` on every poll.
+    #[cfg(not(feature = "task-local"))]
+    pub(crate) fn enter<R>(&mut self, f: impl FnOnce() -> R) -> R {
+        let _guard = self.clone().attach();
+        f()
+    }
+
+    /// Runs `f` with `self` as the context of the current task. Unlike the thread
+    /// local backend, a context attached by `f` without being restored stays in
+    /// `self` and is visible on the next poll.
+    #[cfg(feature = "task-local")]
+    pub(crate) fn enter<R>(&mut self, f: impl FnOnce() -> R) -> R {
+        // Moves the context of the scope back into `self` when `f` returns or panics
+        struct Restore<'a>(&'a mut Context);
+
+        impl Drop for Restore<'_> {
+            fn drop(&mut self) {
+                let scoped = TASK_CONTEXT
+                    .try_with(|cx| cx.try_borrow_mut().map(|mut cx| std::mem::take(&mut *cx)));
+                if let Ok(Ok(cx)) = scoped {
+                    *self.0 = cx;
+                }
+            }
+        }
+
+        let scoped = RefCell::new(std::mem::take(self));
+        TASK_CONTEXT.sync_scope(scoped, || {
+            let _restore = Restore(self);
+            f()
+        })
+    }
 
-        new_context
+    pub fn current_with_value<T: 'static + Send + Sync>(value: T) -> Self {
+        Context::map_current(|cx| cx.with_value(value))
     }
 
     pub fn get<T: 'static>(&self) -> Option<&T> {
-        self.entries
-            .get(&TypeId::of::<T>())
-            .and_then(|rc| rc.downcast_ref())
+        self.get_entry(&EntryKey::of::<T>(None))
     }
 
     pub fn with_value<T: 'static + Send + Sync>(&self, value: T) -> Self {
+        self.with_entry(EntryKey::of::<T>(None), value)
+    }
+
+    pub fn get_keyed<T: 'static>(&self, key: &ContextKey<T>) -> Option<&T> {
+        self.get_entry(&key.entry_key()).or(key.default)
+    }
+
+    pub fn with_keyed_value<T: 'static + Send + Sync>(
+        &self,
+        key: &ContextKey<T>,
+        value: T,
+    ) -> Self {
+        self.with_entry(key.entry_key(), value)
+    }
+
+    pub fn try_move_out_keyed<T: 'static + Send + Sync>(
+        &mut self,
+        key: &ContextKey<T>,
+    ) -> Option<T> {
+        self.take_entry(&key.entry_key())
+    }
+
+    fn get_entry<T: 'static>(&self, key: &EntryKey) -> Option<&T> {
+        self.entries.get(key).and_then(|rc| rc.downcast_ref())
+    }
+
+    fn with_entry<T: 'static + Send + Sync>(&self, key: EntryKey, value: T) -> Self {
+        Context {
+            entries: self.entries.insert(key, Arc::new(value)),
+            cancel: self.cancel.clone(),
+        }
+    }
+
+    /// Moves the value of `T` from `self` into a copy of `to`, still shared
+    /// with the other contexts which hold it
+    pub(crate) fn move_value_into<T: 'static>(&mut self, to: &Context) -> Context {
+        let key = EntryKey::of::<T>(None);
+        let Some(entry) = self.entries.get(&key).cloned() else {
+            return to.clone();
+        };
+        self.entries.remove_mut(&key);
+        Context {
+            entries: to.entries.insert(key, entry),
+            cancel: to.cancel.clone(),
+        }
+    }
+
+    fn take_entry<T: 'static + Send + Sync>(&mut self, key: &EntryKey) -> Option<T> {
+        let rc = self.entries.get(key)?.clone();
+        self.entries.remove_mut(key);
+
+        // Downcast Arc<dyn Any + Send + Sync> to Arc<T>
+        let arc = rc.downcast::<T>().ok()?;
+        // Unwrap Arc<T> to get the inner T, this only succeeds when no other context shares it
+        Arc::try_unwrap(arc).ok()
+    }
+
+    /// 產生一個可以被手動取消的子Context
+    pub fn with_cancel(&self) -> (Self, CancelHandle) {
+        self.with_cancel_node(None)
+    }
+
+    /// 產生一個在`deadline`之後自動取消的子Context, 若父Context的deadline更早則沿用父Context的
+    pub fn with_deadline(&self, deadline: Instant) -> (Self, CancelHandle) {
+        self.with_cancel_node(Some(deadline))
+    }
+
+    /// 產生一個在`timeout`之後自動取消的子Context
+    pub fn with_timeout(&self, timeout: Duration) -> (Self, CancelHandle) {
+        self.with_deadline(Instant::now() + timeout)
+    }
+
+    fn with_cancel_node(&self, deadline: Option<Instant>) -> (Self, CancelHandle) {
+        let node = CancelNode::child(self.cancel.as_ref(), deadline);
         let mut new_context = self.clone();
-        new_context
-            .entries
-            .insert(TypeId::of::<T>(), Arc::new(value));
+        new_context.cancel = Some(node.clone());
+
+        (new_context, CancelHandle::new(node))
+    }
 
-        new_context
+    pub fn deadline(&self) -> Option<Instant> {
+        self.cancel.as_ref().and_then(|node| node.deadline())
     }
 
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel_reason().is_some()
+    }
+
+    /// 若Context已被取消或已超過deadline, 回傳其原因
+    pub fn cancel_reason(&self) -> Option<CancelReason> {
+        self.cancel.as_ref().and_then(|node| node.reason())
+    }
+
+    /// 在Context被取消或超過deadline時完成的future, 沒有設定取消的Context永遠不會完成
+    pub fn cancelled(&self) -> impl Future<Output = ()> + Send + 'static {
+        let node = self.cancel.clone();
+        async move {
+            match node {
+                Some(node) => node.cancelled().await,
+                None => std::future::pending().await,
+            }
+        }
+    }
+
+    /// 把`self`設為目前的Context, 直到回傳的[`ContextGuard`]被drop
+    ///
+    /// [`ContextGuard`]是`!Send`, 在async函式中跨越`.await`持有它會讓整個future不是`Send`,
+    /// 無法交給`tokio::spawn`等需要`Send`的地方. 這時請改用
+    /// [`with_context`](crate::with_context::FutureExt::with_context)或[`scope`](Self::scope)
     pub fn attach(self) -> ContextGuard {
+        #[cfg(feature = "task-local")]
+        let this = {
+            let mut this = Some(self);
+            if let Ok(previous_cx) =
+                TASK_CONTEXT.try_with(|current| current.replace(this.take().unwrap()))
+            {
+                return ContextGuard {
+                    previous_cx: Some(previous_cx),
+                    task_local: true,
+                    _marker: PhantomData,
+                };
+            }
+            this.unwrap()
+        };
+        #[cfg(not(feature = "task-local"))]
+        let this = self;
+
         let previous_cx = CURRENT_CONTEXT
-            .try_with(|current| current.replace(self))
+            .try_with(|current| current.replace(this))
             .ok();
 
         ContextGuard {
             previous_cx,
+            #[cfg(feature = "task-local")]
+            task_local: false,
             _marker: PhantomData,
         }
     }
 
     pub fn try_move_out<T: 'static + Send + Sync>(&mut self) -> Option<T> {
-        self.entries.remove(&TypeId::of::<T>()).and_then(|rc| {
-            // Downcast Arc<dyn Any + Send + Sync> to Arc<T>
-            let arc = rc.downcast::<T>().ok()?;
-            // Unwrap Arc<T> to get the inner T
-            Arc::try_unwrap(arc).ok()
-        })
+        self.take_entry(&EntryKey::of::<T>(None))
     }
 }
 
 impl std::fmt::Debug for Context {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Context")
-            .field("entries", &self.entries.len())
+            .field("entries", &self.entries.size())
+            .field("deadline", &self.deadline())
+            .field("cancelled", &self.is_cancelled())
             .finish()
     }
 }
@@ -85,6 +362,9 @@ impl std::fmt::Debug for Context {
 #[allow(missing_debug_implementations)]
 pub struct ContextGuard {
     previous_cx: Option<Context>,
+    // whether `previous_cx` was taken from the task local or the thread local
+    #[cfg(feature = "task-local")]
+    task_local: bool,
     // ensure this type is !Send as it relies on thread locals
     _marker: PhantomData<*const ()>,
 }
@@ -92,6 +372,12 @@ pub struct ContextGuard {
 impl Drop for ContextGuard {
     fn drop(&mut self) {
         if let Some(previous_cx) = self.previous_cx.take() {
+            #[cfg(feature = "task-local")]
+            if self.task_local {
+                let _ = TASK_CONTEXT.try_with(|current| current.replace(previous_cx));
+                return;
+            }
+
             let _ = CURRENT_CONTEXT.try_with(|current| current.replace(previous_cx));
         }
     }
@@ -99,13 +385,17 @@ impl Drop for ContextGuard {
 
 /// With TypeIds as keys, there's no need to hash them. They are already hashes
 /// themselves, coming from the compiler. The IdHasher holds the u64 of
-/// the TypeId, and then returns it, instead of doing any bit fiddling.
+/// the TypeId, and only mixes in the id of keyed entries.
 #[derive(Clone, Default, Debug)]
 struct IdHasher(u64);
 
 impl Hasher for IdHasher {
-    fn write(&mut self, _: &[u8]) {
-        unreachable!("TypeId calls write_u64");
+    #[inline]
+    fn write(&mut self, bytes: &[u8]) {
+        // FNV-1a over the id of a `ContextKey`
+        for byte in bytes {
+            self.0 = (self.0 ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3);
+        }
     }
 
     #[inline]
@@ -200,4 +490,163 @@ mod test {
         assert_eq!(current.get::<ValueA>(), Some(&ValueA("a")));
         assert_eq!(current.get::<ValueB>(), None);
     }
+
+    #[tokio::test]
+    async fn scope_sets_current_context() {
+        let value = Context::new()
+            .with_value(ValueA("scope"))
+            .scope(async {
+                tokio::task::yield_now().await;
+                Context::map_current(|cx| cx.get::<ValueA>().map(|v| v.0))
+            })
+            .await;
+
+        assert_eq!(value, Some("scope"));
+        assert_eq!(Context::current().get::<ValueA>(), None);
+    }
+
+    #[cfg(feature = "task-local")]
+    #[tokio::test]
+    async fn task_local_attach_survives_await() {
+        Context::new()
+            .scope(async {
+                let guard = Context::current_with_value(ValueB(7)).attach();
+                tokio::task::yield_now().await;
+                assert_eq!(Context::current().get::<ValueB>(), Some(&ValueB(7)));
+                drop(guard);
+                assert_eq!(Context::current().get::<ValueB>(), None);
+            })
+            .await;
+    }
+
+    #[test]
+    fn keyed_values() {
+        static PRIMARY: ContextKey<String> = ContextKey::new("primary");
+        static REPORTING: ContextKey<String> = ContextKey::new("reporting");
+        static TIMEOUT: ContextKey<u64> = ContextKey::with_default("timeout", &30);
+
+        let mut cx = Context::new()
+            .with_value("typed".to_string())
+            .with_keyed_value(&PRIMARY, "primary".to_string())
+            .with_keyed_value(&REPORTING, "reporting".to_string());
+
+        assert_eq!(cx.get::<String>().map(String::as_str), Some("typed"));
+        assert_eq!(cx.get_keyed(&PRIMARY).map(String::as_str), Some("primary"));
+        assert_eq!(
+            cx.get_keyed(&REPORTING).map(String::as_str),
+            Some("reporting")
+        );
+        assert_eq!(cx.get_keyed(&TIMEOUT), Some(&30));
+
+        let cx2 = cx.with_keyed_value(&TIMEOUT, 5);
+        assert_eq!(cx2.get_keyed(&TIMEOUT), Some(&5));
+        drop(cx2);
+
+        assert_eq!(cx.try_move_out_keyed(&PRIMARY), Some("primary".to_string()));
+        assert_eq!(cx.get_keyed(&PRIMARY), None);
+        assert_eq!(
+            cx.get_keyed(&REPORTING).map(String::as_str),
+            Some("reporting")
+        );
+    }
+
+    #[test]
+    fn keys_with_the_same_name_are_apart() {
+        static FIRST: ContextKey<String> = ContextKey::new("name");
+        static SECOND: ContextKey<String> = ContextKey::new("name");
+
+        let cx = Context::new()
+            .with_keyed_value(&FIRST, "first".to_string())
+            .with_keyed_value(&SECOND, "second".to_string());
+
+        assert_eq!(cx.get_keyed(&FIRST).map(String::as_str), Some("first"));
+        assert_eq!(cx.get_keyed(&SECOND).map(String::as_str), Some("second"));
+    }
+
+    #[test]
+    fn derived_contexts_are_independent() {
+        let parent = Context::new().with_value(ValueA("parent"));
+        let mut child = parent.with_value(ValueB(1)).with_value(ValueA("child"));
+
+        assert_eq!(parent.get::<ValueA>(), Some(&ValueA("parent")));
+        assert_eq!(parent.get::<ValueB>(), None);
+        assert_eq!(child.get::<ValueA>(), Some(&ValueA("child")));
+
+        // `ValueB` is only owned by the child, `ValueA("parent")` is still shared
+        assert_eq!(child.try_move_out::<ValueB>(), Some(ValueB(1)));
+        let mut sibling = parent.clone();
+        assert_eq!(sibling.try_move_out::<ValueA>(), None);
+        assert_eq!(sibling.get::<ValueA>(), None);
+        assert_eq!(parent.get::<ValueA>(), Some(&ValueA("parent")));
+    }
+
+    #[tokio::test]
+    async fn cancel_propagates_to_children() {
+        let (parent, handle) = Context::new().with_value(ValueA("a")).with_cancel();
+        let (child, _child_handle) = parent.with_cancel();
+        let grandchild = child.with_value(ValueB(42));
+
+        assert!(!grandchild.is_cancelled());
+        assert_eq!(grandchild.get::<ValueA>(), Some(&ValueA("a")));
+
+        let waiter = tokio::spawn(grandchild.cancelled());
+        handle.cancel();
+        waiter.await.unwrap();
+
+        assert_eq!(child.cancel_reason(), Some(CancelReason::Cancelled));
+        assert_eq!(grandchild.cancel_reason(), Some(CancelReason::Cancelled));
+
+        // A child of a cancelled context starts cancelled
+        let (late_child, _) = parent.with_cancel();
+        assert!(late_child.is_cancelled());
+    }
+
+    #[tokio::test]
+    async fn child_cancel_does_not_affect_parent() {
+        let (parent, _handle) = Context::new().with_cancel();
+        let (child, child_handle) = parent.with_cancel();
+
+        child_handle.cancel();
+        assert!(child.is_cancelled());
+        assert!(!parent.is_cancelled());
+    }
+
+    #[tokio::test]
+    async fn deadline_is_inherited() {
+        let (parent, _handle) = Context::new().with_timeout(Duration::from_millis(20));
+        let (child, _) = parent.with_timeout(Duration::from_secs(60));
+        assert_eq!(child.deadline(), parent.deadline());
+
+        let (inner, _) = parent.with_cancel();
+        assert_eq!(inner.deadline(), parent.deadline());
+
+        inner.cancelled().await;
+        assert_eq!(child.cancel_reason(), Some(CancelReason::DeadlineExceeded));
+        assert!(Context::new().deadline().is_none());
+    }
+
+    #[tokio::test]
+    async fn first_cancel_reason_is_kept() {
+        let (cx, handle) = Context::new().with_timeout(Duration::from_millis(20));
+        handle.cancel();
+        cx.cancelled().await;
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert_eq!(cx.cancel_reason(), Some(CancelReason::Cancelled));
+
+        let (cx, handle) = Context::new().with_timeout(Duration::from_millis(10));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        handle.cancel();
+        assert_eq!(cx.cancel_reason(), Some(CancelReason::DeadlineExceeded));
+    }
+
+    #[test]
+    fn enter_keeps_context_on_panic() {
+        let mut cx = Context::new().with_value(ValueA("enter"));
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            cx.enter(|| panic!("poll panicked"))
+        }));
+
+        assert!(result.is_err());
+        assert_eq!(cx.get::<ValueA>(), Some(&ValueA("enter")));
+    }
 }
@@ -1,28 +1,152 @@
 use pin_project_lite::pin_project;
+use std::net::SocketAddr;
+use std::sync::Arc;
 use std::task::{Context as TaskContext, Poll};
+use tonic::metadata::MetadataMap;
+use tonic::transport::server::{TcpConnectInfo, TlsConnectInfo};
 use tower::Service;
 
 use crate::context::Context;
+use crate::propagation::Propagator;
 use crate::with_context::{FutureExt, WithContext};
 
-#[derive(Debug, Clone)]
-pub struct ContextService<S> {
+/// 目前請求的gRPC method path, 例如`/test.TestService/SaveMsg`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GrpcMethod(pub String);
+
+impl GrpcMethod {
+    /// 完整的service名稱, 例如`test.TestService`
+    pub fn service(&self) -> Option<&str> {
+        self.0
+            .trim_start_matches('/')
+            .split_once('/')
+            .map(|(s, _)| s)
+    }
+
+    /// method名稱, 例如`SaveMsg`
+    pub fn method(&self) -> Option<&str> {
+        self.0
+            .trim_start_matches('/')
+            .split_once('/')
+            .map(|(_, m)| m)
+    }
+}
+
+/// 目前請求的對端位址
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerAddr(pub SocketAddr);
+
+/// 為每個請求產生Context, `base`已包含[`GrpcMethod`], [`PeerAddr`]與[`Propagator`]讀出的值
+pub trait ContextFactory<B>: Send + Sync + 'static {
+    fn create(&self, base: &Context, request: &http::Request<B>) -> Context;
+}
+
+impl<B, F> ContextFactory<B> for F
+where
+    F: Fn(&Context, &http::Request<B>) -> Context + Send + Sync + 'static,
+{
+    fn create(&self, base: &Context, request: &http::Request<B>) -> Context {
+        self(base, request)
+    }
+}
+
+/// 直接使用`base`的[`ContextFactory`]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BaseContext;
+
+impl<B> ContextFactory<B> for BaseContext {
+    fn create(&self, base: &Context, _request: &http::Request<B>) -> Context {
+        base.clone()
+    }
+}
+
+pub struct ContextService<S, F = BaseContext> {
     inner: S,
     context: Context,
+    propagators: Arc<[Arc<dyn Propagator>]>,
+    factory: Arc<F>,
+}
+
+impl<S, F> ContextService<S, F> {
+    fn new(
+        inner: S,
+        context: Context,
+        propagators: Arc<[Arc<dyn Propagator>]>,
+        factory: Arc<F>,
+    ) -> Self {
+        ContextService {
+            inner,
+            context,
+            propagators,
+            factory,
+        }
+    }
+
+    fn request_context<B>(&self, request: &http::Request<B>) -> Context
+    where
+        F: ContextFactory<B>,
+    {
+        let mut context = self
+            .context
+            .with_value(GrpcMethod(request.uri().path().to_string()));
+
+        // Same lookup as `tonic::Request::remote_addr`, TLS connections wrap the TCP info
+        let peer_addr = request
+            .extensions()
+            .get::<TcpConnectInfo>()
+            .and_then(TcpConnectInfo::remote_addr)
+            .or_else(|| {
+                request
+                    .extensions()
+                    .get::<TlsConnectInfo<TcpConnectInfo>>()
+                    .and_then(|info| info.get_ref().remote_addr())
+            });
+        if let Some(peer_addr) = peer_addr {
+            context = context.with_value(PeerAddr(peer_addr));
+        }
+
+        if !self.propagators.is_empty() {
+            let metadata = MetadataMap::from_headers(request.headers().clone());
+            context = self
+                .propagators
+                .iter()
+                .fold(context, |context, propagator| {
+                    propagator.extract(context, &metadata)
+                });
+        }
+
+        self.factory.create(&context, request)
+    }
+}
+
+impl<S: Clone, F> Clone for ContextService<S, F> {
+    fn clone(&self) -> Self {
+        ContextService {
+            inner: self.inner.clone(),
+            context: self.context.clone(),
+            propagators: self.propagators.clone(),
+            factory: self.factory.clone(),
+        }
+    }
 }
 
-impl<S> ContextService<S> {
-    fn new(inner: S, context: Context) -> Self {
-        ContextService { inner, context }
+impl<S: std::fmt::Debug, F> std::fmt::Debug for ContextService<S, F> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ContextService")
+            .field("inner", &self.inner)
+            .field("context", &self.context)
+            .field("propagators", &self.propagators.len())
+            .finish()
     }
 }
 
-impl<S, Request> Service<Request> for ContextService<S>
+impl<S, F, B, ResBody> Service<http::Request<B>> for ContextService<S, F>
 where
-    S: Service<Request>,
-    Request: Send + 'static,
+    S: Service<http::Request<B>, Response = http::Response<ResBody>>,
+    F: ContextFactory<B>,
+    B: Send + 'static,
 {
-    type Response = S::Response;
+    type Response = http::Response<WithContext<ResBody>>;
     type Error = S::Error;
     type Future = FutureResponse<S::Future>;
 
@@ -30,9 +154,10 @@ where
         self.inner.poll_ready(cx).map_err(Into::into)
     }
 
-    fn call(&mut self, request: Request) -> Self::Future {
-        // let _guard = self.context.clone().attach();
-        let response_future = self.inner.call(request).with_context(self.context.clone());
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        let mut context = self.request_context(&request);
+        let inner = &mut self.inner;
+        let response_future = context.enter(|| inner.call(request)).with_context(context);
 
         FutureResponse { response_future }
     }
@@ -45,33 +170,254 @@ pin_project! {
     }
 }
 
-impl<F, Response, Error> std::future::Future for FutureResponse<F>
+impl<F, ResBody, Error> std::future::Future for FutureResponse<F>
 where
-    F: std::future::Future<Output = Result<Response, Error>>,
+    F: std::future::Future<Output = Result<http::Response<ResBody>, Error>>,
 {
-    type Output = Result<Response, Error>;
+    type Output = Result<http::Response<WithContext<ResBody>>, Error>;
 
     fn poll(self: std::pin::Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Self::Output> {
-        let this = self.project();
-        this.response_future.poll(cx)
+        let mut this = self.project();
+        let response = futures_core::ready!(this.response_future.as_mut().poll(cx));
+
+        // The body of a streaming RPC is polled after this future completes, so it
+        // needs its own copy of the context
+        let context = this.response_future.context().clone();
+        Poll::Ready(response.map(|response| response.map(|body| body.with_context(context))))
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct ContextHolder {
+pub struct ContextHolder<F = BaseContext> {
     context: Context,
+    propagators: Vec<Arc<dyn Propagator>>,
+    factory: Arc<F>,
 }
 
 impl ContextHolder {
     pub fn new(context: Context) -> Self {
-        ContextHolder { context }
+        ContextHolder {
+            context,
+            propagators: Vec::new(),
+            factory: Arc::new(BaseContext),
+        }
+    }
+}
+
+impl<F> ContextHolder<F> {
+    /// 註冊一個[`Propagator`], 每個請求都會依註冊順序從metadata讀取值放入Context
+    pub fn with_propagator(mut self, propagator: impl Propagator) -> Self {
+        self.propagators.push(Arc::new(propagator));
+        self
+    }
+
+    /// 以`factory`為每個請求產生新的子Context, 例如`|base: &Context, req: &http::Request<_>| base.with_value(..)`
+    pub fn with_factory<G>(self, factory: G) -> ContextHolder<G> {
+        ContextHolder {
+            context: self.context,
+            propagators: self.propagators,
+            factory: Arc::new(factory),
+        }
+    }
+}
+
+impl<F> Clone for ContextHolder<F> {
+    fn clone(&self) -> Self {
+        ContextHolder {
+            context: self.context.clone(),
+            propagators: self.propagators.clone(),
+            factory: self.factory.clone(),
+        }
     }
 }
 
-impl<S> tower::Layer<S> for ContextHolder {
-    type Service = ContextService<S>;
+impl<F> std::fmt::Debug for ContextHolder<F> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ContextHolder")
+            .field("context", &self.context)
+            .field("propagators", &self.propagators.len())
+            .finish()
+    }
+}
+
+impl<S, F> tower::Layer<S> for ContextHolder<F> {
+    type Service = ContextService<S, F>;
 
     fn layer(&self, inner: S) -> Self::Service {
-        ContextService::new(inner, self.context.clone())
+        ContextService::new(
+            inner,
+            self.context.clone(),
+            self.propagators.clone().into(),
+            self.factory.clone(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::propagation::{self, RequestId};
+    use http_body::Body;
+    use std::future::{ready, Ready};
+    use std::pin::Pin;
+    use tonic::codegen::Bytes;
+    use tower::Layer;
+
+    struct CurrentContext;
+
+    impl Service<http::Request<()>> for CurrentContext {
+        type Response = http::Response<Context>;
+        type Error = std::convert::Infallible;
+        type Future = Ready<Result<Self::Response, Self::Error>>;
+
+        fn poll_ready(&mut self, _: &mut TaskContext<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _: http::Request<()>) -> Self::Future {
+            ready(Ok(http::Response::new(Context::current())))
+        }
+    }
+
+    async fn call_for_context<S>(service: &mut S, request: http::Request<()>) -> Context
+    where
+        S: Service<
+            http::Request<()>,
+            Response = http::Response<WithContext<Context>>,
+            Error = std::convert::Infallible,
+        >,
+    {
+        service
+            .call(request)
+            .await
+            .unwrap()
+            .into_body()
+            .into_inner()
+    }
+
+    /// A streaming body which yields the request id of the current context on every poll
+    struct RequestIdStream {
+        remaining: usize,
+    }
+
+    impl http_body::Body for RequestIdStream {
+        type Data = Bytes;
+        type Error = std::convert::Infallible;
+
+        fn poll_data(
+            mut self: Pin<&mut Self>,
+            _: &mut TaskContext<'_>,
+        ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+            if self.remaining == 0 {
+                return Poll::Ready(None);
+            }
+            self.remaining -= 1;
+
+            let request_id = Context::current()
+                .get::<RequestId>()
+                .map(|id| id.0.clone())
+                .unwrap_or_default();
+            Poll::Ready(Some(Ok(Bytes::from(request_id))))
+        }
+
+        fn poll_trailers(
+            self: Pin<&mut Self>,
+            _: &mut TaskContext<'_>,
+        ) -> Poll<Result<Option<http::HeaderMap>, Self::Error>> {
+            Poll::Ready(Ok(None))
+        }
+    }
+
+    struct Streaming;
+
+    impl Service<http::Request<()>> for Streaming {
+        type Response = http::Response<RequestIdStream>;
+        type Error = std::convert::Infallible;
+        type Future = Ready<Result<Self::Response, Self::Error>>;
+
+        fn poll_ready(&mut self, _: &mut TaskContext<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _: http::Request<()>) -> Self::Future {
+            ready(Ok(http::Response::new(RequestIdStream { remaining: 2 })))
+        }
+    }
+
+    #[tokio::test]
+    async fn streaming_body_runs_inside_context() {
+        let mut service = ContextHolder::new(Context::new())
+            .with_propagator(propagation::request_id())
+            .layer(Streaming);
+
+        let request = http::Request::builder()
+            .header("x-request-id", "stream")
+            .body(())
+            .unwrap();
+        let mut body = service.call(request).await.unwrap().into_body();
+
+        // Polled outside of any context, like hyper does for a streaming response
+        assert_eq!(Context::current().get::<RequestId>(), None);
+        while let Some(chunk) = body.data().await {
+            assert_eq!(chunk.unwrap(), "stream");
+        }
+    }
+
+    #[tokio::test]
+    async fn propagators_populate_request_context() {
+        let mut service = ContextHolder::new(Context::new())
+            .with_propagator(propagation::request_id())
+            .layer(CurrentContext);
+
+        let request = http::Request::builder()
+            .header("x-request-id", "abc")
+            .body(())
+            .unwrap();
+        let cx = call_for_context(&mut service, request).await;
+        assert_eq!(cx.get::<RequestId>(), Some(&RequestId("abc".to_string())));
+
+        let cx = call_for_context(&mut service, http::Request::new(())).await;
+        assert_eq!(cx.get::<RequestId>(), None);
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct Authority(String);
+
+    #[tokio::test]
+    async fn factory_builds_context_per_request() {
+        let mut service = ContextHolder::new(Context::new())
+            .with_propagator(propagation::request_id())
+            .with_factory(|base: &Context, req: &http::Request<()>| {
+                // The defaults and the propagated values are already in `base`
+                assert!(base.get::<GrpcMethod>().is_some());
+                base.with_value(Authority(req.uri().host().unwrap_or_default().to_string()))
+            })
+            .layer(CurrentContext);
+
+        let peer: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        let mut request = http::Request::builder()
+            .uri("http://example.com/test.TestService/SaveMsg")
+            .header("x-request-id", "abc")
+            .body(())
+            .unwrap();
+        request.extensions_mut().insert(TcpConnectInfo {
+            local_addr: None,
+            remote_addr: Some(peer),
+        });
+
+        let cx = call_for_context(&mut service, request).await;
+        let method = cx.get::<GrpcMethod>().unwrap();
+        assert_eq!(method.0, "/test.TestService/SaveMsg");
+        assert_eq!(method.service(), Some("test.TestService"));
+        assert_eq!(method.method(), Some("SaveMsg"));
+        assert_eq!(cx.get::<PeerAddr>(), Some(&PeerAddr(peer)));
+        assert_eq!(cx.get::<RequestId>(), Some(&RequestId("abc".to_string())));
+        assert_eq!(
+            cx.get::<Authority>(),
+            Some(&Authority("example.com".to_string()))
+        );
+
+        let cx = call_for_context(&mut service, http::Request::new(())).await;
+        assert_eq!(cx.get::<PeerAddr>(), None);
+        assert_eq!(cx.get::<Authority>(), Some(&Authority(String::new())));
     }
 }
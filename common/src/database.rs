@@ -1,15 +1,471 @@
 use std::any::Any;
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::marker::PhantomData;
+use std::panic::AssertUnwindSafe;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
+use futures::FutureExt as _;
 use tonic::async_trait;
 
 use crate::context::Context;
+use crate::with_context::FutureExt;
+
+/// `#[transactional]`取得transaction的方式, 語意與Spring的`Propagation`相同
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Propagation {
+    /// 沿用Context中的transaction, 沒有時建立新的
+    #[default]
+    Required,
+    /// 總是建立新的transaction, 外層的transaction在執行期間不會被使用
+    RequiresNew,
+    /// 在Context中的transaction裡建立savepoint, 沒有時建立新的
+    Nested,
+    /// 沿用Context中的transaction, 沒有時不使用transaction
+    Supports,
+    /// 沿用Context中的transaction, 沒有時回傳錯誤
+    Mandatory,
+    /// 不使用transaction, Context中已有transaction時回傳錯誤
+    Never,
+}
+
+/// 依照[`Propagation`]取得transaction時發生的錯誤
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransactionError {
+    /// Context中沒有transaction, 例如[`Propagation::Mandatory`]或建立savepoint時
+    TransactionRequired,
+    /// [`Propagation::Never`]但Context中已經有transaction
+    TransactionNotAllowed,
+    /// Context中沒有對應型別的[`Database`]
+    DatabaseNotFound(&'static str),
+    /// 資料庫不支援two-phase commit
+    TwoPhaseNotSupported,
+    /// 其他Context仍持有transaction, 例如尚未消耗完的stream或背景task, transaction不會被commit
+    TransactionStillInUse,
+    /// Context中的transaction沒有hook, 只有`create_transaction_in_context`建立的transaction可以註冊hook
+    HooksNotFound,
+}
+
+impl std::fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransactionError::TransactionRequired => {
+                f.write_str("no existing transaction found in the context")
+            }
+            TransactionError::TransactionNotAllowed => {
+                f.write_str("existing transaction found for propagation `never`")
+            }
+            TransactionError::DatabaseNotFound(name) => {
+                write!(f, "the DB struct `{}` not found in the context", name)
+            }
+            TransactionError::TwoPhaseNotSupported => {
+                f.write_str("two-phase commit is not supported by the database")
+            }
+            TransactionError::TransactionStillInUse => {
+                f.write_str("the transaction is still used by another context")
+            }
+            TransactionError::HooksNotFound => {
+                f.write_str("the transaction in the context does not support hooks")
+            }
+        }
+    }
+}
+
+impl std::error::Error for TransactionError {}
+
+/// transaction的隔離等級
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IsolationLevel {
+    ReadUncommitted,
+    ReadCommitted,
+    RepeatableRead,
+    Serializable,
+}
+
+/// transaction是否可以寫入
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessMode {
+    ReadOnly,
+    ReadWrite,
+}
+
+/// 建立transaction時的設定, 未指定的欄位使用資料庫的預設值
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TransactionOptions {
+    pub isolation: Option<IsolationLevel>,
+    pub access_mode: Option<AccessMode>,
+    /// 只在`Serializable`且`ReadOnly`的transaction中有效
+    pub deferrable: bool,
+}
+
+impl TransactionOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn isolation(mut self, isolation: IsolationLevel) -> Self {
+        self.isolation = Some(isolation);
+        self
+    }
+
+    pub fn access_mode(mut self, access_mode: AccessMode) -> Self {
+        self.access_mode = Some(access_mode);
+        self
+    }
+
+    pub fn read_only(self) -> Self {
+        self.access_mode(AccessMode::ReadOnly)
+    }
+
+    pub fn deferrable(mut self) -> Self {
+        self.deferrable = true;
+        self
+    }
+}
+
+/// 可以透過重新執行整個transaction解決的錯誤, 例如serialization failure與deadlock
+pub trait RetryableError {
+    fn is_retryable(&self) -> bool;
+}
+
+/// 重試transaction前的等待時間
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backoff {
+    None,
+    Fixed(Duration),
+    /// 從`initial`開始每次加倍, 最多等待`max`
+    Exponential {
+        initial: Duration,
+        max: Duration,
+    },
+}
+
+impl Backoff {
+    pub fn fixed() -> Self {
+        Backoff::Fixed(Duration::from_millis(50))
+    }
+
+    pub fn exponential() -> Self {
+        Backoff::Exponential {
+            initial: Duration::from_millis(10),
+            max: Duration::from_secs(1),
+        }
+    }
+
+    /// 第`attempt`次執行失敗後的等待時間, `attempt`從1開始
+    pub fn delay(&self, attempt: u32) -> Duration {
+        match *self {
+            Backoff::None => Duration::ZERO,
+            Backoff::Fixed(delay) => delay,
+            Backoff::Exponential { initial, max } => {
+                let factor = 1u32 << attempt.saturating_sub(1).min(31);
+                initial.saturating_mul(factor).min(max)
+            }
+        }
+    }
+}
+
+/// `#[transactional(retry = 3, backoff = "exponential")]`使用的重試策略
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// 第一次執行之外最多重試的次數
+    pub retries: u32,
+    pub backoff: Backoff,
+}
+
+impl RetryPolicy {
+    pub fn new(retries: u32, backoff: Backoff) -> Self {
+        RetryPolicy { retries, backoff }
+    }
+
+    /// 第`attempt`次執行因`error`失敗後是否要重試
+    pub fn should_retry<E: RetryableError + ?Sized>(&self, attempt: u32, error: &E) -> bool {
+        attempt <= self.retries && error.is_retryable()
+    }
+
+    pub async fn wait(&self, attempt: u32) {
+        let delay = self.backoff.delay(attempt);
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+/// 記錄[`TransactionGuard`]執行期間資料庫回傳的最後一個錯誤是否可以重試
+///
+/// `#[transactional(retry = ..)]`依此決定是否重試, 判斷發生在`map_err`/`From`轉換之前,
+/// 因此函數的錯誤型別不需要實作[`RetryableError`]. [`ContextConnection`](crate::db_impl::ContextConnection)
+/// 會自動記錄, 直接使用transaction時請以[`RetryTracker::record_in`]回報錯誤
+#[derive(Debug, Default)]
+pub struct RetryTracker {
+    retryable: AtomicBool,
+    /// The tracker of the enclosing guard, which may retry the whole transaction
+    parent: Option<Arc<RetryTracker>>,
+}
+
+impl RetryTracker {
+    /// 記錄`error`, 外層guard的tracker也會一併記錄
+    pub fn record<E: RetryableError + ?Sized>(&self, error: &E) {
+        let retryable = error.is_retryable();
+        let mut tracker = Some(self);
+        while let Some(current) = tracker {
+            current.retryable.store(retryable, Ordering::Relaxed);
+            tracker = current.parent.as_deref();
+        }
+    }
+
+    /// 記錄到`context`中的tracker, 不在[`TransactionGuard`]中時不做任何事
+    pub fn record_in<E: RetryableError + ?Sized>(context: &Context, error: &E) {
+        if let Some(tracker) = context.get::<Arc<RetryTracker>>() {
+            tracker.record(error);
+        }
+    }
+}
+
+impl RetryableError for RetryTracker {
+    fn is_retryable(&self) -> bool {
+        self.retryable.load(Ordering::Relaxed)
+    }
+}
+
+/// 區分同一種資料庫的多個實例, 例如`NamedPostgres<Primary>`與`NamedPostgres<Analytics>`
+pub trait DatabaseMarker: Send + Sync + 'static {}
+
+/// 沒有指定標記的資料庫, transaction不經[`Tagged`]包裝, 直接放在Context中
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DefaultDatabase;
+
+/// 以標記`M`區分的`T`, 讓同型別的transaction可以同時放在Context中
+pub struct Tagged<T, M> {
+    inner: T,
+    _marker: PhantomData<fn() -> M>,
+}
+
+impl<T, M> Tagged<T, M> {
+    pub fn new(inner: T) -> Self {
+        Tagged {
+            inner,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T, M> std::ops::Deref for Tagged<T, M> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T, M> std::ops::DerefMut for Tagged<T, M> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+impl<T: std::fmt::Debug, M> std::fmt::Debug for Tagged<T, M> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Tagged")
+            .field("inner", &self.inner)
+            .field("marker", &std::any::type_name::<M>())
+            .finish()
+    }
+}
+
+/// [`Database::begin_in_context`]的結果, 決定結束時如何處理transaction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransactionScope {
+    /// 沿用外層的transaction或沒有使用transaction, 由外層負責commit或rollback
+    Joined,
+    /// 建立了新的transaction
+    Transaction,
+    /// 在外層的transaction中建立了savepoint
+    Savepoint,
+}
+
+type Hook = Box<dyn FnOnce() -> Pin<Box<dyn Future<Output = ()> + Send>> + Send>;
+
+#[derive(Default)]
+struct Hooks {
+    on_commit: Vec<Hook>,
+    on_rollback: Vec<Hook>,
+}
+
+/// 與`D`的transaction一起放在Context中, 在transaction的結果確定後執行註冊的hook
+///
+/// savepoint有自己的hook: release時併入外層transaction, rollback時只執行`on_rollback`
+pub struct TransactionHooks<D> {
+    hooks: Arc<Mutex<Hooks>>,
+    /// The hooks of the enclosing transaction, only set for savepoints
+    parent: Option<Arc<Mutex<Hooks>>>,
+    _database: PhantomData<fn() -> D>,
+}
+
+impl<D> TransactionHooks<D> {
+    fn new(parent: Option<Arc<Mutex<Hooks>>>) -> Self {
+        TransactionHooks {
+            hooks: Arc::default(),
+            parent,
+            _database: PhantomData,
+        }
+    }
+
+    /// 在transaction成功commit後執行
+    pub fn on_commit<F, Fut>(&self, hook: F)
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let hook: Hook = Box::new(move || Box::pin(hook()));
+        self.hooks.lock().unwrap().on_commit.push(hook);
+    }
+
+    /// 在transaction rollback或commit失敗後執行
+    pub fn on_rollback<F, Fut>(&self, hook: F)
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let hook: Hook = Box::new(move || Box::pin(hook()));
+        self.hooks.lock().unwrap().on_rollback.push(hook);
+    }
+
+    fn take(&self) -> Hooks {
+        std::mem::take(&mut *self.hooks.lock().unwrap())
+    }
+
+    async fn committed(self) {
+        for hook in self.take().on_commit {
+            hook().await;
+        }
+    }
+
+    async fn rolled_back(self) {
+        for hook in self.take().on_rollback {
+            hook().await;
+        }
+    }
+
+    fn released(self) {
+        let hooks = self.take();
+        if let Some(parent) = &self.parent {
+            let mut parent = parent.lock().unwrap();
+            parent.on_commit.extend(hooks.on_commit);
+            parent.on_rollback.extend(hooks.on_rollback);
+        }
+    }
+}
+
+impl<D> Clone for TransactionHooks<D> {
+    fn clone(&self) -> Self {
+        TransactionHooks {
+            hooks: self.hooks.clone(),
+            parent: self.parent.clone(),
+            _database: PhantomData,
+        }
+    }
+}
+
+impl<D> std::fmt::Debug for TransactionHooks<D> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let hooks = self.hooks.lock().unwrap();
+        f.debug_struct("TransactionHooks")
+            .field("on_commit", &hooks.on_commit.len())
+            .field("on_rollback", &hooks.on_rollback.len())
+            .field("savepoint", &self.parent.is_some())
+            .finish()
+    }
+}
+
+/// Removes the hooks from `context`, even when another context still shares them
+fn take_hooks<D: 'static>(context: &mut Context) -> Option<TransactionHooks<D>> {
+    let hooks = context.get::<TransactionHooks<D>>().cloned();
+    context.try_move_out::<TransactionHooks<D>>();
+    hooks
+}
+
+/// Moves the transaction and its hooks out of `context`. When another context
+/// still shares the transaction it is only dropped from `context`, its writes
+/// are lost once the last copy is dropped, so the rollback hooks run
+async fn take_transaction<D: Database>(
+    context: &mut Context,
+) -> Result<Option<(D::DatabaseTransaction, Option<TransactionHooks<D>>)>, D::DatabaseError> {
+    if context.get::<D::DatabaseTransaction>().is_none() {
+        return Ok(None);
+    }
+    let txn = context.try_move_out::<D::DatabaseTransaction>();
+    let hooks = take_hooks::<D>(context);
+    match txn {
+        Some(txn) => Ok(Some((txn, hooks))),
+        None => {
+            if let Some(hooks) = hooks {
+                hooks.rolled_back().await;
+            }
+            Err(TransactionError::TransactionStillInUse.into())
+        }
+    }
+}
+
+/// Hooks can only be registered with a transaction which will run them
+fn hooks_in_context<D: Database>(
+    context: &Context,
+) -> Result<&TransactionHooks<D>, TransactionError> {
+    match context.get::<TransactionHooks<D>>() {
+        Some(hooks) => Ok(hooks),
+        None if context.get::<D::DatabaseTransaction>().is_some() => {
+            Err(TransactionError::HooksNotFound)
+        }
+        None => Err(TransactionError::TransactionRequired),
+    }
+}
+
+/// Moves the transaction of `D` and its hooks into a context of their own, so
+/// ending it can not take the transactions of the other databases along
+fn isolate<D: Database>(context: &mut Context) -> Context {
+    let isolated = context.move_value_into::<D::DatabaseTransaction>(&Context::new());
+    context.move_value_into::<TransactionHooks<D>>(&isolated)
+}
+
 #[async_trait]
-pub trait Database: Any + Send + Sync {
+pub trait Database: Any + Send + Sync + Sized {
     type DatabaseConnection;
     type DatabaseTransaction: Any + Send + Sync;
-    type DatabaseError;
+    type DatabaseError: From<TransactionError> + Send;
+
+    async fn create_transaction(&self) -> Result<Self::DatabaseTransaction, Self::DatabaseError> {
+        self.create_transaction_with(TransactionOptions::default())
+            .await
+    }
+
+    async fn create_transaction_with(
+        &self,
+        options: TransactionOptions,
+    ) -> Result<Self::DatabaseTransaction, Self::DatabaseError>;
 
-    async fn create_transaction(&self) -> Result<Self::DatabaseTransaction, Self::DatabaseError>;
+    /// 在`transaction`中建立savepoint, 回傳的transaction中的操作可以單獨rollback
+    async fn create_savepoint(
+        transaction: &Self::DatabaseTransaction,
+    ) -> Result<Self::DatabaseTransaction, Self::DatabaseError>;
+
+    /// 復原自savepoint建立以來的操作, 外層的transaction不受影響
+    async fn rollback_to_savepoint(
+        savepoint: Self::DatabaseTransaction,
+    ) -> Result<(), Self::DatabaseError>;
+
+    /// 保留自savepoint建立以來的操作, 是否寫入仍由外層的transaction決定
+    async fn release_savepoint(
+        savepoint: Self::DatabaseTransaction,
+    ) -> Result<(), Self::DatabaseError>;
 
     async fn rollback_transaction(
         transaction: Self::DatabaseTransaction,
@@ -19,29 +475,853 @@ pub trait Database: Any + Send + Sync {
         transaction: Self::DatabaseTransaction,
     ) -> Result<(), Self::DatabaseError>;
 
+    /// 以two-phase commit與其他資料庫一起commit時的名稱, 預設為`None`而不使用two-phase commit
+    ///
+    /// 名稱記錄在prepared transaction的id中, 讓[`TwoPhaseRecovery`]找出coordinator, 各資料庫的名稱必須不同且重新啟動後保持不變.
+    /// 英數字與`_`以外的字元會被編碼, Postgres的id最多200 bytes, 名稱應保持簡短
+    fn two_phase_name(&self) -> Option<&str> {
+        None
+    }
+
+    /// transaction中是否有需要保存的寫入, 沒有寫入的transaction不需要two-phase commit, 預設視為有寫入
+    async fn has_writes(
+        _transaction: &Self::DatabaseTransaction,
+    ) -> Result<bool, Self::DatabaseError> {
+        Ok(true)
+    }
+
+    /// two-phase commit的第一階段, 之後只能以`id`呼叫`commit_prepared`或`rollback_prepared`
+    async fn prepare_transaction(
+        _transaction: Self::DatabaseTransaction,
+        _id: &str,
+    ) -> Result<(), Self::DatabaseError> {
+        Err(TransactionError::TwoPhaseNotSupported.into())
+    }
+
+    async fn commit_prepared(&self, _id: &str) -> Result<(), Self::DatabaseError> {
+        Err(TransactionError::TwoPhaseNotSupported.into())
+    }
+
+    async fn rollback_prepared(&self, _id: &str) -> Result<(), Self::DatabaseError> {
+        Err(TransactionError::TwoPhaseNotSupported.into())
+    }
+
+    /// 列出以[`TWO_PHASE_ID_PREFIX`]開頭, 依資料庫的時鐘已經prepare超過`older_than`且仍未完成的transaction id
+    async fn prepared_transactions(
+        &self,
+        _older_than: Duration,
+    ) -> Result<Vec<String>, Self::DatabaseError> {
+        Err(TransactionError::TwoPhaseNotSupported.into())
+    }
+
     async fn create_transaction_in_context(
         &self,
         context: Context,
     ) -> Result<Context, Self::DatabaseError> {
-        let txn = self.create_transaction().await?;
-        Ok(context.with_value(txn))
+        self.create_transaction_in_context_with(context, TransactionOptions::default())
+            .await
+    }
+
+    async fn create_transaction_in_context_with(
+        &self,
+        context: Context,
+        options: TransactionOptions,
+    ) -> Result<Context, Self::DatabaseError> {
+        let txn = self.create_transaction_with(options).await?;
+        Ok(context
+            .with_value(txn)
+            .with_value(TransactionHooks::<Self>::new(None)))
+    }
+
+    /// 註冊在Context中的transaction成功commit後執行的hook
+    fn on_commit<F, Fut>(context: &Context, hook: F) -> Result<(), Self::DatabaseError>
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let hooks = hooks_in_context::<Self>(context)?;
+        hooks.on_commit(hook);
+        Ok(())
+    }
+
+    /// 註冊在Context中的transaction rollback後執行的hook
+    fn on_rollback<F, Fut>(context: &Context, hook: F) -> Result<(), Self::DatabaseError>
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let hooks = hooks_in_context::<Self>(context)?;
+        hooks.on_rollback(hook);
+        Ok(())
+    }
+
+    /// 依照`propagation`沿用或建立transaction, 結束時以回傳的[`TransactionScope`]
+    /// 呼叫`commit_scope_in_context`或`rollback_scope_in_context`
+    ///
+    /// `options`只在建立新的transaction時使用, 沿用的transaction與savepoint維持外層的設定
+    async fn begin_in_context(
+        &self,
+        context: Context,
+        propagation: Propagation,
+        options: TransactionOptions,
+    ) -> Result<(Context, TransactionScope), Self::DatabaseError> {
+        let existing = context.get::<Self::DatabaseTransaction>().is_some();
+        match (propagation, existing) {
+            (Propagation::Required | Propagation::Mandatory | Propagation::Supports, true)
+            | (Propagation::Supports | Propagation::Never, false) => {
+                Ok((context, TransactionScope::Joined))
+            }
+            (Propagation::Mandatory, false) => Err(TransactionError::TransactionRequired.into()),
+            (Propagation::Never, true) => Err(TransactionError::TransactionNotAllowed.into()),
+            (Propagation::Nested, true) => Ok((
+                Self::create_savepoint_in_context(context).await?,
+                TransactionScope::Savepoint,
+            )),
+            (Propagation::Required | Propagation::RequiresNew | Propagation::Nested, _) => Ok((
+                self.create_transaction_in_context_with(context, options)
+                    .await?,
+                TransactionScope::Transaction,
+            )),
+        }
+    }
+
+    async fn commit_scope_in_context(
+        context: Context,
+        scope: TransactionScope,
+    ) -> Result<Context, Self::DatabaseError> {
+        match scope {
+            TransactionScope::Joined => Ok(context),
+            TransactionScope::Transaction => Self::commit_transaction_in_context(context).await,
+            TransactionScope::Savepoint => Self::release_savepoint_in_context(context).await,
+        }
+    }
+
+    async fn rollback_scope_in_context(
+        context: Context,
+        scope: TransactionScope,
+    ) -> Result<Context, Self::DatabaseError> {
+        match scope {
+            TransactionScope::Joined => Ok(context),
+            TransactionScope::Transaction => Self::rollback_transaction_in_context(context).await,
+            TransactionScope::Savepoint => Self::rollback_to_savepoint_in_context(context).await,
+        }
+    }
+
+    /// 在Context中的transaction建立savepoint, 回傳的Context以savepoint取代原本的transaction
+    async fn create_savepoint_in_context(context: Context) -> Result<Context, Self::DatabaseError> {
+        let txn = context
+            .get::<Self::DatabaseTransaction>()
+            .ok_or(TransactionError::TransactionRequired)?;
+        let savepoint = Self::create_savepoint(txn).await?;
+
+        // Released hooks move into the hooks of the transaction, without them
+        // nothing would run the hooks, so the savepoint has none either
+        let hooks = context
+            .get::<TransactionHooks<Self>>()
+            .map(|parent| TransactionHooks::<Self>::new(Some(parent.hooks.clone())));
+        let context = context.with_value(savepoint);
+        Ok(match hooks {
+            Some(hooks) => context.with_value(hooks),
+            None => context,
+        })
+    }
+
+    /// 回傳的Context不再包含savepoint, 後續請繼續使用建立savepoint前的Context
+    async fn rollback_to_savepoint_in_context(
+        mut context: Context,
+    ) -> Result<Context, Self::DatabaseError> {
+        if let Some((savepoint, hooks)) = take_transaction::<Self>(&mut context).await? {
+            let result = Self::rollback_to_savepoint(savepoint).await;
+            if let Some(hooks) = hooks {
+                hooks.rolled_back().await;
+            }
+            result?;
+        }
+        Ok(context)
+    }
+
+    /// 回傳的Context不再包含savepoint, 後續請繼續使用建立savepoint前的Context
+    async fn release_savepoint_in_context(
+        mut context: Context,
+    ) -> Result<Context, Self::DatabaseError> {
+        if let Some((savepoint, hooks)) = take_transaction::<Self>(&mut context).await? {
+            let result = Self::release_savepoint(savepoint).await;
+            if let Some(hooks) = hooks {
+                match result {
+                    Ok(()) => hooks.released(),
+                    Err(_) => hooks.rolled_back().await,
+                }
+            }
+            result?;
+        }
+        Ok(context)
     }
 
     async fn rollback_transaction_in_context(
         mut context: Context,
     ) -> Result<Context, Self::DatabaseError> {
-        if let Some(txn) = context.try_move_out::<Self::DatabaseTransaction>() {
-            Self::rollback_transaction(txn).await?;
+        if let Some((txn, hooks)) = take_transaction::<Self>(&mut context).await? {
+            let result = Self::rollback_transaction(txn).await;
+            // A failed rollback still ends the transaction without committing it
+            if let Some(hooks) = hooks {
+                hooks.rolled_back().await;
+            }
+            result?;
         }
         Ok(context)
     }
 
+    /// 把Context中的transaction移出並prepare, hook在[`PreparedTransaction`]完成時才執行
+    async fn prepare_transaction_in_context(
+        context: &mut Context,
+        id: &str,
+    ) -> Result<Option<PreparedTransaction<Self>>, Self::DatabaseError> {
+        let Some((txn, hooks)) = take_transaction::<Self>(context).await? else {
+            return Ok(None);
+        };
+        match Self::prepare_transaction(txn, id).await {
+            Ok(()) => Ok(Some(PreparedTransaction {
+                id: id.to_string(),
+                hooks,
+            })),
+            Err(e) => {
+                if let Some(hooks) = hooks {
+                    hooks.rolled_back().await;
+                }
+                Err(e)
+            }
+        }
+    }
+
     async fn commit_transaction_in_context(
         mut context: Context,
     ) -> Result<Context, Self::DatabaseError> {
-        if let Some(txn) = context.try_move_out::<Self::DatabaseTransaction>() {
-            Self::commit_transaction(txn).await?;
+        if let Some((txn, hooks)) = take_transaction::<Self>(&mut context).await? {
+            let result = Self::commit_transaction(txn).await;
+            if let Some(hooks) = hooks {
+                match result {
+                    Ok(()) => hooks.committed().await,
+                    Err(_) => hooks.rolled_back().await,
+                }
+            }
+            result?;
         }
         Ok(context)
     }
 }
+
+type Rollback = Box<dyn FnOnce(&mut Context) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send>;
+
+const FINISHED: &str = "TransactionGuard already finished";
+
+/// 保存`#[transactional]`建立的transaction, 從建立transaction到commit或rollback完成前
+/// 被drop或本體panic時rollback
+///
+/// 被drop時未完成的commit或rollback會以`tokio::spawn`在背景執行, 不在tokio runtime中時只會drop transaction
+pub struct TransactionGuard {
+    context: Option<Context>,
+    retry: Arc<RetryTracker>,
+    rollbacks: Vec<Rollback>,
+    /// The commit or rollback in progress, finished in the background when dropped
+    pending: Option<Pin<Box<dyn Future<Output = ()> + Send>>>,
+}
+
+impl TransactionGuard {
+    pub fn new(context: Context) -> Self {
+        let retry = Arc::new(RetryTracker {
+            retryable: AtomicBool::new(false),
+            parent: context.get::<Arc<RetryTracker>>().cloned(),
+        });
+        TransactionGuard {
+            context: Some(context.with_value(retry.clone())),
+            retry,
+            rollbacks: Vec::new(),
+            pending: None,
+        }
+    }
+
+    /// 記錄這個guard執行期間資料庫錯誤的[`RetryTracker`]
+    pub fn retry_tracker(&self) -> &Arc<RetryTracker> {
+        &self.retry
+    }
+
+    /// 依照`propagation`在guard的Context中沿用或建立`D`的transaction, 並登記rollback
+    pub async fn begin<D: Database>(
+        &mut self,
+        propagation: Propagation,
+        options: TransactionOptions,
+    ) -> Result<TransactionScope, D::DatabaseError> {
+        let context = self.context.clone().expect(FINISHED);
+        let database = context
+            .get::<D>()
+            .ok_or(TransactionError::DatabaseNotFound(
+                std::any::type_name::<D>(),
+            ))?;
+        let (context, scope) = database
+            .begin_in_context(context.clone(), propagation, options)
+            .await?;
+        self.context = Some(context);
+        self.push::<D>(scope);
+        Ok(scope)
+    }
+
+    /// 登記需要rollback的transaction, [`TransactionScope::Joined`]會被忽略
+    pub fn push<D: Database>(&mut self, scope: TransactionScope) {
+        if scope == TransactionScope::Joined {
+            return;
+        }
+        self.rollbacks.push(Box::new(move |context| {
+            let context = isolate::<D>(context);
+            Box::pin(async move {
+                let _ = D::rollback_scope_in_context(context, scope).await;
+            })
+        }));
+    }
+
+    /// 在guard的Context中執行`future`, panic時先rollback再繼續panic
+    pub async fn run<F: Future>(&mut self, future: F) -> F::Output {
+        let context = self.context.clone().expect(FINISHED);
+
+        // The future is dropped at the end of this statement, which releases
+        // its copy of the context before the rollback moves the transactions out
+        let result = AssertUnwindSafe(future.with_context(context))
+            .catch_unwind()
+            .await;
+
+        match result {
+            Ok(output) => output,
+            Err(panic) => {
+                self.rollback().await;
+                std::panic::resume_unwind(panic)
+            }
+        }
+    }
+
+    /// 以`commit`結束登記的transaction
+    pub async fn commit<E: Send + 'static>(
+        &mut self,
+        commit: TwoPhaseCommit<E>,
+    ) -> Result<(), CommitError<E>> {
+        let context = self.context.take().expect(FINISHED);
+        self.rollbacks.clear();
+        self.complete(async move { commit.commit(context).await.map(drop) })
+            .await
+    }
+
+    /// rollback所有登記的transaction, 其中一個失敗時仍會繼續rollback其他的
+    pub async fn rollback(&mut self) {
+        let Some(context) = self.context.take() else {
+            return;
+        };
+        let rollbacks = std::mem::take(&mut self.rollbacks);
+        self.complete(rollback(context, rollbacks)).await
+    }
+
+    /// Runs `work` as the pending work, so dropping the guard before it is
+    /// done finishes it in the background instead of abandoning it halfway
+    async fn complete<T: Send + 'static>(
+        &mut self,
+        work: impl Future<Output = T> + Send + 'static,
+    ) -> T {
+        let (sender, mut receiver) = tokio::sync::oneshot::channel();
+        let pending = self.pending.insert(Box::pin(async move {
+            let _ = sender.send(work.await);
+        }));
+        pending.await;
+        self.pending = None;
+        receiver.try_recv().expect("the pending work has finished")
+    }
+}
+
+impl Drop for TransactionGuard {
+    fn drop(&mut self) {
+        let work = match self.pending.take() {
+            Some(pending) => pending,
+            None => {
+                let Some(context) = self.context.take() else {
+                    return;
+                };
+                if self.rollbacks.is_empty() {
+                    return;
+                }
+                Box::pin(rollback(context, std::mem::take(&mut self.rollbacks)))
+            }
+        };
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            handle.spawn(work);
+        }
+    }
+}
+
+impl std::fmt::Debug for TransactionGuard {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TransactionGuard")
+            .field("context", &self.context)
+            .field("retry", &self.retry)
+            .field("rollbacks", &self.rollbacks.len())
+            .field("pending", &self.pending.is_some())
+            .finish()
+    }
+}
+
+/// Every transaction is rolled back on its own context, so a failed rollback
+/// does not keep the others from being rolled back
+async fn rollback(mut context: Context, rollbacks: Vec<Rollback>) {
+    for rollback in rollbacks {
+        rollback(&mut context).await;
+    }
+}
+
+/// two-phase commit的transaction id的開頭, 用來找出`#[transactional]`prepare的transaction
+pub const TWO_PHASE_ID_PREFIX: &str = "ctx2pc-";
+
+/// `{prefix}{random}-{coordinator}`, the coordinator part tells recovery which
+/// database decides the outcome of the transaction
+fn two_phase_id(coordinator: &str) -> String {
+    format!(
+        "{}{:016x}{:016x}-{}",
+        TWO_PHASE_ID_PREFIX,
+        random_u64(),
+        random_u64(),
+        coordinator
+    )
+}
+
+/// `{id}-{participant}`, prepared transaction ids are unique within a whole
+/// Postgres server, which may hold several of the databases
+fn participant_id(id: &str, participant: &str) -> String {
+    format!("{}-{}", id, participant)
+}
+
+/// The id shared by every participant of the transaction
+fn shared_id(participant_id: &str) -> &str {
+    participant_id
+        .rsplit_once('-')
+        .map_or(participant_id, |(id, _)| id)
+}
+
+fn two_phase_coordinator(id: &str) -> Option<&str> {
+    let mut parts = id.strip_prefix(TWO_PHASE_ID_PREFIX)?.split('-');
+    let (_random, coordinator, _participant) = (parts.next()?, parts.next()?, parts.next()?);
+    parts.next().is_none().then_some(coordinator)
+}
+
+/// The whole name from `two_phase_name`, with every byte but alphanumerics and
+/// `_` percent-encoded so that it never contains the `-` between the parts
+fn two_phase_key(name: &str) -> String {
+    name.bytes().fold(String::new(), |mut key, byte| {
+        if byte.is_ascii_alphanumeric() || byte == b'_' {
+            key.push(char::from(byte));
+        } else {
+            key.push_str(&format!("%{:02X}", byte));
+        }
+        key
+    })
+}
+
+fn random_u64() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+/// 已經prepare, 等待commit或rollback的transaction
+pub struct PreparedTransaction<D> {
+    id: String,
+    hooks: Option<TransactionHooks<D>>,
+}
+
+impl<D: Database> PreparedTransaction<D> {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// 失敗時transaction可能仍是prepared, 結果交由[`TwoPhaseRecovery`]決定, hook不會執行
+    pub async fn commit(self, database: &D) -> Result<(), D::DatabaseError> {
+        database.commit_prepared(&self.id).await?;
+        if let Some(hooks) = self.hooks {
+            hooks.committed().await;
+        }
+        Ok(())
+    }
+
+    pub async fn rollback(self, database: &D) -> Result<(), D::DatabaseError> {
+        database.rollback_prepared(&self.id).await?;
+        if let Some(hooks) = self.hooks {
+            hooks.rolled_back().await;
+        }
+        Ok(())
+    }
+}
+
+impl<D> std::fmt::Debug for PreparedTransaction<D> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PreparedTransaction")
+            .field("id", &self.id)
+            .field("hooks", &self.hooks)
+            .finish()
+    }
+}
+
+/// [`TwoPhaseCommit::commit`]失敗時的錯誤
+#[derive(Debug)]
+pub struct CommitError<E> {
+    pub error: E,
+    /// 部分資料庫可能已經commit, 或仍有prepared transaction等待[`TwoPhaseRecovery`], 這時不能重試
+    pub in_doubt: bool,
+}
+
+/// One database of a [`TwoPhaseCommit`], erasing its type
+#[async_trait]
+trait Participant<E>: Send {
+    fn scope(&self) -> TransactionScope;
+
+    fn two_phase_name<'a>(&self, context: &'a Context) -> Option<&'a str>;
+
+    async fn commit(&mut self, context: &mut Context) -> Result<(), E>;
+
+    async fn rollback(&mut self, context: &mut Context) -> Result<(), E>;
+
+    async fn has_writes(&mut self, context: &Context) -> Result<bool, E>;
+
+    async fn prepare(&mut self, context: &mut Context, id: &str) -> Result<(), E>;
+
+    async fn commit_prepared(&mut self, context: &Context) -> Result<(), E>;
+
+    async fn rollback_prepared(&mut self, context: &Context) -> Result<(), E>;
+}
+
+struct Member<D, F> {
+    scope: TransactionScope,
+    convert: F,
+    prepared: Option<PreparedTransaction<D>>,
+}
+
+impl<D, E, F> Member<D, F>
+where
+    D: Database,
+    F: Fn(D::DatabaseError) -> E,
+{
+    fn database<'a>(&self, context: &'a Context) -> Result<&'a D, E> {
+        context.get::<D>().ok_or_else(|| {
+            (self.convert)(TransactionError::DatabaseNotFound(std::any::type_name::<D>()).into())
+        })
+    }
+}
+
+#[async_trait]
+impl<D, E, F> Participant<E> for Member<D, F>
+where
+    D: Database,
+    E: Send + 'static,
+    F: Fn(D::DatabaseError) -> E + Send + Sync + 'static,
+{
+    fn scope(&self) -> TransactionScope {
+        self.scope
+    }
+
+    fn two_phase_name<'a>(&self, context: &'a Context) -> Option<&'a str> {
+        context.get::<D>().and_then(D::two_phase_name)
+    }
+
+    async fn commit(&mut self, context: &mut Context) -> Result<(), E> {
+        if self.scope == TransactionScope::Joined {
+            return Ok(());
+        }
+        D::commit_scope_in_context(isolate::<D>(context), self.scope)
+            .await
+            .map(drop)
+            .map_err(&self.convert)
+    }
+
+    async fn rollback(&mut self, context: &mut Context) -> Result<(), E> {
+        if self.scope == TransactionScope::Joined {
+            return Ok(());
+        }
+        D::rollback_scope_in_context(isolate::<D>(context), self.scope)
+            .await
+            .map(drop)
+            .map_err(&self.convert)
+    }
+
+    async fn has_writes(&mut self, context: &Context) -> Result<bool, E> {
+        match context.get::<D::DatabaseTransaction>() {
+            Some(transaction) => D::has_writes(transaction).await.map_err(&self.convert),
+            None => Ok(false),
+        }
+    }
+
+    async fn prepare(&mut self, context: &mut Context, id: &str) -> Result<(), E> {
+        self.prepared = D::prepare_transaction_in_context(context, id)
+            .await
+            .map_err(&self.convert)?;
+        Ok(())
+    }
+
+    async fn commit_prepared(&mut self, context: &Context) -> Result<(), E> {
+        let database = self.database(context)?;
+        match self.prepared.take() {
+            Some(prepared) => prepared.commit(database).await.map_err(&self.convert),
+            None => Ok(()),
+        }
+    }
+
+    async fn rollback_prepared(&mut self, context: &Context) -> Result<(), E> {
+        let database = self.database(context)?;
+        match self.prepared.take() {
+            Some(prepared) => prepared.rollback(database).await.map_err(&self.convert),
+            None => Ok(()),
+        }
+    }
+}
+
+/// `#[transactional]`列出多個資料庫時的commit
+///
+/// 有兩個以上的資料庫建立了有寫入的transaction且都支援two-phase commit時, 依序prepare後再依序commit,
+/// 否則依序commit. 第一個有寫入的資料庫是coordinator: 最先prepare, 最先commit, 最後rollback,
+/// 因此[`TwoPhaseRecovery`]可以從它的狀態判斷其他資料庫中遺留的transaction該如何處理
+pub struct TwoPhaseCommit<E> {
+    participants: Vec<Box<dyn Participant<E>>>,
+}
+
+impl<E: Send + 'static> TwoPhaseCommit<E> {
+    pub fn new() -> Self {
+        TwoPhaseCommit {
+            participants: Vec::new(),
+        }
+    }
+
+    /// 依照`#[transactional]`中的順序登記資料庫, `convert`把資料庫的錯誤轉成函數的錯誤
+    pub fn push<D: Database>(
+        &mut self,
+        scope: TransactionScope,
+        convert: impl Fn(D::DatabaseError) -> E + Send + Sync + 'static,
+    ) {
+        self.participants.push(Box::new(Member::<D, _> {
+            scope,
+            convert,
+            prepared: None,
+        }));
+    }
+
+    pub async fn commit(self, mut context: Context) -> Result<Context, CommitError<E>> {
+        let (mut owned, mut joined): (Vec<_>, Vec<_>) = self
+            .participants
+            .into_iter()
+            .partition(|participant| participant.scope() == TransactionScope::Transaction);
+
+        let two_phase = owned.len() > 1
+            && owned
+                .iter()
+                .all(|participant| participant.two_phase_name(&context).is_some());
+        if !two_phase {
+            joined.append(&mut owned);
+            return Self::commit_in_sequence(context, joined).await;
+        }
+
+        // Transactions without writes do not need two-phase commit. They are
+        // committed together with the savepoints first, nothing is durable if that fails
+        let mut writes = Vec::with_capacity(owned.len());
+        for participant in &mut owned {
+            match participant.has_writes(&context).await {
+                Ok(has_writes) => writes.push(has_writes),
+                Err(error) => {
+                    Self::rollback_all(&mut context, &mut joined).await;
+                    Self::rollback_all(&mut context, &mut owned).await;
+                    return Err(CommitError {
+                        error,
+                        in_doubt: false,
+                    });
+                }
+            }
+        }
+        let mut writers = Vec::with_capacity(owned.len());
+        for (participant, has_writes) in owned.into_iter().zip(writes) {
+            if has_writes {
+                writers.push(participant);
+            } else {
+                joined.push(participant);
+            }
+        }
+        for i in 0..joined.len() {
+            if let Err(error) = joined[i].commit(&mut context).await {
+                Self::rollback_all(&mut context, &mut joined[i + 1..]).await;
+                Self::rollback_all(&mut context, &mut writers).await;
+                return Err(CommitError {
+                    error,
+                    in_doubt: false,
+                });
+            }
+        }
+        if writers.len() < 2 {
+            return Self::commit_in_sequence(context, writers).await;
+        }
+
+        let id = two_phase_id(&two_phase_key(
+            writers[0].two_phase_name(&context).unwrap_or_default(),
+        ));
+        for i in 0..writers.len() {
+            let name = writers[i].two_phase_name(&context).unwrap_or_default();
+            let participant_id = participant_id(&id, &two_phase_key(name));
+            let Err(error) = writers[i].prepare(&mut context, &participant_id).await else {
+                continue;
+            };
+
+            // Stops at the first failure, which leaves the coordinator prepared,
+            // recovery then rolls back everything. Until then the prepared ones
+            // hold their locks, so a retry is not attempted
+            let mut stuck = false;
+            for participant in writers[..i].iter_mut().rev() {
+                if participant.rollback_prepared(&context).await.is_err() {
+                    stuck = true;
+                    break;
+                }
+            }
+            Self::rollback_all(&mut context, &mut writers[i + 1..]).await;
+            return Err(CommitError {
+                error,
+                in_doubt: stuck,
+            });
+        }
+
+        // Committing the coordinator decides the outcome, when that fails it is
+        // unknown and left to recovery. Afterwards the others are committed in
+        // any case and only the first error is returned
+        let mut first_error = None;
+        for (i, participant) in writers.iter_mut().enumerate() {
+            if let Err(error) = participant.commit_prepared(&context).await {
+                if i == 0 {
+                    return Err(CommitError {
+                        error,
+                        in_doubt: true,
+                    });
+                }
+                first_error.get_or_insert(error);
+            }
+        }
+        match first_error {
+            Some(error) => Err(CommitError {
+                error,
+                in_doubt: true,
+            }),
+            None => Ok(context),
+        }
+    }
+
+    /// A failed commit rolls back the participants after it
+    async fn commit_in_sequence(
+        mut context: Context,
+        mut participants: Vec<Box<dyn Participant<E>>>,
+    ) -> Result<Context, CommitError<E>> {
+        let mut committed = false;
+        for i in 0..participants.len() {
+            if let Err(error) = participants[i].commit(&mut context).await {
+                Self::rollback_all(&mut context, &mut participants[i + 1..]).await;
+                return Err(CommitError {
+                    error,
+                    in_doubt: committed,
+                });
+            }
+            committed |= participants[i].scope() == TransactionScope::Transaction;
+        }
+        Ok(context)
+    }
+
+    /// A failed rollback does not keep the others from being rolled back
+    async fn rollback_all(context: &mut Context, participants: &mut [Box<dyn Participant<E>>]) {
+        for participant in participants {
+            let _ = participant.rollback(context).await;
+        }
+    }
+}
+
+impl<E: Send + 'static> Default for TwoPhaseCommit<E> {
+    fn default() -> Self {
+        TwoPhaseCommit::new()
+    }
+}
+
+impl<E> std::fmt::Debug for TwoPhaseCommit<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TwoPhaseCommit")
+            .field("participants", &self.participants.len())
+            .finish()
+    }
+}
+
+/// 處理程式在two-phase commit途中結束時遺留的prepared transaction
+///
+/// 每個資料庫都可能是coordinator, 先對所有資料庫呼叫`scan`記錄它們作為coordinator仍是prepared的transaction,
+/// 再對所有資料庫呼叫`resolve`, 全部成功後才對所有資料庫呼叫`finish`rollback它們作為coordinator的transaction
+#[derive(Debug)]
+pub struct TwoPhaseRecovery {
+    older_than: Duration,
+    started: Instant,
+    /// The transactions still prepared in each scanned coordinator, by its key
+    pending: HashMap<String, HashSet<String>>,
+}
+
+impl TwoPhaseRecovery {
+    /// 只處理在`new`時已經prepare超過`older_than`的transaction, 它必須遠大於一次commit所需的時間
+    pub fn new(older_than: Duration) -> Self {
+        TwoPhaseRecovery {
+            older_than,
+            started: Instant::now(),
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Every database is filtered by the same point in time, a transaction
+    /// prepared after it may still be in progress. It is measured back from
+    /// now by the clock of each database, so their clocks need not agree
+    fn older_than(&self) -> Duration {
+        self.older_than + self.started.elapsed()
+    }
+
+    /// 記錄`database`作為coordinator時仍是prepared的transaction
+    pub async fn scan<D: Database>(&mut self, database: &D) -> Result<(), D::DatabaseError> {
+        let key = Self::key(database)?;
+        let pending = database
+            .prepared_transactions(self.older_than())
+            .await?
+            .into_iter()
+            .filter(|id| two_phase_coordinator(id) == Some(key.as_str()))
+            .collect();
+        self.pending.insert(key, pending);
+        Ok(())
+    }
+
+    /// 在coordinator中仍是prepared的transaction被rollback, 其餘的代表coordinator已經commit而跟著commit,
+    /// coordinator沒有`scan`過的transaction不會處理
+    pub async fn resolve<D: Database>(&self, database: &D) -> Result<(), D::DatabaseError> {
+        let key = Self::key(database)?;
+        for id in database.prepared_transactions(self.older_than()).await? {
+            let Some(coordinator) = two_phase_coordinator(&id).filter(|&other| other != key) else {
+                continue;
+            };
+            // The id of the same transaction in the coordinator
+            let coordinator_id = participant_id(shared_id(&id), coordinator);
+            match self.pending.get(coordinator) {
+                Some(pending) if pending.contains(&coordinator_id) => {
+                    database.rollback_prepared(&id).await?
+                }
+                Some(_) => database.commit_prepared(&id).await?,
+                None => {}
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn finish<D: Database>(&self, database: &D) -> Result<(), D::DatabaseError> {
+        let key = Self::key(database)?;
+        for id in self.pending.get(&key).into_iter().flatten() {
+            database.rollback_prepared(id).await?;
+        }
+        Ok(())
+    }
+
+    fn key<D: Database>(database: &D) -> Result<String, TransactionError> {
+        database
+            .two_phase_name()
+            .map(two_phase_key)
+            .ok_or(TransactionError::TwoPhaseNotSupported)
+    }
+}
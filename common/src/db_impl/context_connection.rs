@@ -0,0 +1,311 @@
+use std::{future::Future, marker::PhantomData, pin::Pin};
+
+use futures::{Stream, StreamExt};
+use sea_orm::{
+    ConnectionTrait, DatabaseConnection, DbBackend, DbErr, ExecResult, QueryResult, Statement,
+    StreamTrait,
+};
+use tonic::async_trait;
+
+use crate::context::Context;
+use crate::database::{DefaultDatabase, RetryTracker, TransactionError};
+use crate::db_impl::{PostgresMarker, SeaOrmPostgres};
+
+/// 每次查詢時從`Context::current()`取得連線, 有transaction時使用transaction, 否則使用primary.
+/// 標記`M`的連線使用[`NamedPostgres<M>`](crate::db_impl::NamedPostgres)
+///
+/// 查詢失敗時錯誤會記錄到[`RetryTracker`], 讓`#[transactional(retry = ..)]`判斷是否重試
+///
+/// ``` ignore
+/// entity.insert(&ContextConnection).await?;
+/// order::Entity::find().all(&ContextConnection::<Analytics>::new()).await?;
+/// // Reads outside of a transaction on a replica, which may lag behind the primary
+/// order::Entity::find().all(&ContextConnection.replica()).await?;
+/// ```
+pub struct ContextConnection<M = DefaultDatabase> {
+    replica: bool,
+    _marker: PhantomData<fn() -> M>,
+}
+
+/// 沒有標記的[`ContextConnection`], 可直接寫成`&ContextConnection`
+#[allow(non_upper_case_globals)]
+pub const ContextConnection: ContextConnection = ContextConnection {
+    replica: false,
+    _marker: PhantomData,
+};
+
+impl<M: PostgresMarker> ContextConnection<M> {
+    pub const fn new() -> Self {
+        ContextConnection {
+            replica: false,
+            _marker: PhantomData,
+        }
+    }
+
+    /// 不在transaction中的查詢使用`replica()`, 寫入(`execute`)仍使用primary
+    ///
+    /// replica的資料可能落後primary, 剛寫入的資料不一定讀得到, `SELECT ... FOR UPDATE`與
+    /// `nextval`等會寫入的查詢在replica上會失敗, 這些請使用預設的連線或transaction
+    pub const fn replica(self) -> Self {
+        ContextConnection {
+            replica: true,
+            _marker: PhantomData,
+        }
+    }
+
+    /// 讀取使用的連線池
+    fn reader(&self) -> fn(&SeaOrmPostgres) -> &DatabaseConnection {
+        if self.replica {
+            SeaOrmPostgres::replica
+        } else {
+            SeaOrmPostgres::primary
+        }
+    }
+}
+
+impl<M: PostgresMarker> Default for ContextConnection<M> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<M> Clone for ContextConnection<M> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<M> Copy for ContextConnection<M> {}
+
+impl<M> std::fmt::Debug for ContextConnection<M> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ContextConnection")
+            .field("replica", &self.replica)
+            .field("marker", &std::any::type_name::<M>())
+            .finish()
+    }
+}
+
+/// Context中的transaction, 沒有時為`pool`選擇的連線池
+fn resolve<M: PostgresMarker>(
+    context: &Context,
+    pool: fn(&SeaOrmPostgres) -> &DatabaseConnection,
+) -> Result<&dyn ConnectionTrait, DbErr> {
+    if let Some(transaction) = M::transaction(context) {
+        return Ok(transaction);
+    }
+    postgres::<M>(context).map(|db| pool(db) as &dyn ConnectionTrait)
+}
+
+fn postgres<M: PostgresMarker>(context: &Context) -> Result<&SeaOrmPostgres, DbErr> {
+    context
+        .get::<M::Postgres>()
+        .map(M::postgres)
+        .ok_or_else(not_found::<M>)
+}
+
+fn not_found<M: PostgresMarker>() -> DbErr {
+    TransactionError::DatabaseNotFound(std::any::type_name::<M::Postgres>()).into()
+}
+
+#[async_trait]
+impl<M: PostgresMarker> ConnectionTrait for ContextConnection<M> {
+    fn get_database_backend(&self) -> DbBackend {
+        DbBackend::Postgres
+    }
+
+    async fn execute(&self, stmt: Statement) -> Result<ExecResult, DbErr> {
+        let context = Context::current();
+        resolve::<M>(&context, SeaOrmPostgres::primary)?
+            .execute(stmt)
+            .await
+            .inspect_err(|error| RetryTracker::record_in(&context, error))
+    }
+
+    async fn execute_unprepared(&self, sql: &str) -> Result<ExecResult, DbErr> {
+        let context = Context::current();
+        resolve::<M>(&context, SeaOrmPostgres::primary)?
+            .execute_unprepared(sql)
+            .await
+            .inspect_err(|error| RetryTracker::record_in(&context, error))
+    }
+
+    async fn query_one(&self, stmt: Statement) -> Result<Option<QueryResult>, DbErr> {
+        let context = Context::current();
+        resolve::<M>(&context, self.reader())?
+            .query_one(stmt)
+            .await
+            .inspect_err(|error| RetryTracker::record_in(&context, error))
+    }
+
+    async fn query_all(&self, stmt: Statement) -> Result<Vec<QueryResult>, DbErr> {
+        let context = Context::current();
+        resolve::<M>(&context, self.reader())?
+            .query_all(stmt)
+            .await
+            .inspect_err(|error| RetryTracker::record_in(&context, error))
+    }
+}
+
+impl<M: PostgresMarker> StreamTrait for ContextConnection<M> {
+    type Stream<'a> = Pin<Box<dyn Stream<Item = Result<QueryResult, DbErr>> + Send + 'a>>;
+
+    fn stream<'a>(
+        &'a self,
+        stmt: Statement,
+    ) -> Pin<Box<dyn Future<Output = Result<Self::Stream<'a>, DbErr>> + 'a + Send>> {
+        let context = Context::current();
+        Box::pin(async move {
+            if M::transaction(&context).is_none() {
+                let db = postgres::<M>(&context)?;
+                let stream: Self::Stream<'a> = Box::pin(self.reader()(db).stream(stmt).await?);
+                return Ok(stream);
+            }
+
+            // The rows borrow the transaction, so the stream keeps the context alive
+            // until it is dropped. Committing before that fails with
+            // `TransactionStillInUse` and the transaction is rolled back.
+            let stream: Self::Stream<'a> = Box::pin(async_stream::try_stream! {
+                let transaction = M::transaction(&context)
+                    .expect("transaction checked above");
+                let record = |error: &DbErr| RetryTracker::record_in(&context, error);
+                let mut rows = transaction.stream(stmt).await.inspect_err(record)?;
+                while let Some(row) = rows.next().await {
+                    yield row.inspect_err(record)?;
+                }
+            });
+            Ok(stream)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use sea_orm::MockDatabaseConnection;
+
+    use super::*;
+    use crate::database::Database;
+    use crate::db_impl::sea_orm_postgres::tests::{connection, mock};
+
+    fn logged(db: &Arc<MockDatabaseConnection>) -> Vec<String> {
+        connection(db)
+            .into_transaction_log()
+            .iter()
+            .flat_map(|txn| txn.statements().iter().map(|stmt| stmt.sql.clone()))
+            .collect()
+    }
+
+    fn statement(sql: &str) -> Statement {
+        Statement::from_string(DbBackend::Postgres, sql)
+    }
+
+    #[tokio::test]
+    async fn missing_database_is_an_error() {
+        let stmt = Statement::from_string(DbBackend::Postgres, "SELECT 1");
+        let error = Context::new()
+            .scope(async { ContextConnection.query_one(stmt).await })
+            .await
+            .unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            DbErr::from(TransactionError::DatabaseNotFound(std::any::type_name::<
+                SeaOrmPostgres,
+            >()))
+            .to_string()
+        );
+    }
+
+    #[tokio::test]
+    async fn only_replica_reads_use_a_replica() {
+        let (primary, replica) = (mock(), mock());
+        let context = Context::new().with_value(SeaOrmPostgres::with_connections(
+            connection(&primary),
+            vec![connection(&replica)],
+        ));
+
+        context
+            .scope(async {
+                // The mocks have no results, only the logged statements matter
+                let _ = ContextConnection
+                    .query_one(statement("SELECT nextval('seq')"))
+                    .await;
+                let _ = ContextConnection
+                    .replica()
+                    .query_all(statement("SELECT 1"))
+                    .await;
+                let _ = ContextConnection
+                    .replica()
+                    .stream(statement("SELECT 2"))
+                    .await;
+                let _ = ContextConnection
+                    .replica()
+                    .execute(statement("DELETE FROM t"))
+                    .await;
+            })
+            .await;
+
+        assert_eq!(logged(&replica), ["SELECT 1", "SELECT 2"]);
+        assert_eq!(logged(&primary), ["SELECT nextval('seq')", "DELETE FROM t"]);
+    }
+
+    #[tokio::test]
+    async fn the_transaction_is_preferred_over_the_pool() {
+        let (pool, other) = (mock(), mock());
+        let transaction = SeaOrmPostgres::with_connections(connection(&other), vec![])
+            .create_transaction()
+            .await
+            .unwrap();
+        let context = Context::new()
+            .with_value(SeaOrmPostgres::with_connections(connection(&pool), vec![]))
+            .with_value(transaction);
+
+        let context = context
+            .scope(async {
+                let _ = ContextConnection
+                    .replica()
+                    .query_all(statement("SELECT 1"))
+                    .await;
+                let _ = ContextConnection.execute(statement("DELETE FROM t")).await;
+                Context::current()
+            })
+            .await;
+        SeaOrmPostgres::commit_transaction_in_context(context)
+            .await
+            .unwrap();
+
+        assert!(logged(&pool).is_empty());
+        assert_eq!(
+            logged(&other),
+            ["BEGIN", "SELECT 1", "DELETE FROM t", "COMMIT"]
+        );
+    }
+
+    #[tokio::test]
+    async fn commit_fails_while_a_stream_is_alive() {
+        let db = mock();
+        let postgres = SeaOrmPostgres::with_connections(connection(&db), vec![]);
+        let context = postgres
+            .create_transaction_in_context(Context::new())
+            .await
+            .unwrap();
+
+        let rows = context
+            .clone()
+            .scope(async { ContextConnection.stream(statement("SELECT 1")).await })
+            .await
+            .unwrap();
+        let error = SeaOrmPostgres::commit_transaction_in_context(context)
+            .await
+            .unwrap_err();
+        drop(rows);
+
+        assert_eq!(
+            error.to_string(),
+            DbErr::from(TransactionError::TransactionStillInUse).to_string()
+        );
+        assert!(!logged(&db).contains(&"COMMIT".to_string()));
+    }
+}
@@ -1,3 +1,7 @@
+mod context_connection;
+mod postgres_config;
 mod sea_orm_postgres;
 
+pub use context_connection::*;
+pub use postgres_config::*;
 pub use sea_orm_postgres::*;
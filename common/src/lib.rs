@@ -1,5 +1,11 @@
+pub mod cancel;
+pub mod client_middleware;
 pub mod context;
 pub mod context_middleware;
 pub mod database;
 pub mod db_impl;
+pub mod propagation;
+pub mod task;
 pub mod with_context;
+
+pub use task::{spawn, spawn_blocking, spawn_local, JoinSet};
@@ -0,0 +1,367 @@
+use std::marker::PhantomData;
+use std::time::{Duration, Instant};
+
+use tonic::metadata::{AsciiMetadataKey, AsciiMetadataValue, MetadataMap};
+
+use crate::context::{Context, ContextKey};
+
+mod w3c;
+
+pub use w3c::*;
+
+/// [`Propagator`]負責在[`Context`]與gRPC metadata之間轉換特定的值
+pub trait Propagator: Send + Sync + 'static {
+    /// 把`context`中的值寫入`metadata`, 用於發出的請求
+    fn inject(&self, context: &Context, metadata: &mut MetadataMap);
+
+    /// 從`metadata`讀出值並放入`context`, 用於收到的請求
+    fn extract(&self, context: Context, metadata: &MetadataMap) -> Context;
+}
+
+/// 可以直接以單一metadata欄位表示的Context值
+pub trait MetadataCodec: Sized + Send + Sync + 'static {
+    /// metadata的key, 必須是小寫的ASCII
+    const KEY: &'static str;
+
+    fn encode(&self) -> Option<String>;
+
+    fn decode(value: &str) -> Option<Self>;
+}
+
+/// 以[`MetadataCodec`]在`Context`與metadata之間傳遞`T`
+pub struct CodecPropagator<T> {
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T: MetadataCodec> CodecPropagator<T> {
+    pub fn new() -> Self {
+        CodecPropagator {
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T: MetadataCodec> Default for CodecPropagator<T> {
+    fn default() -> Self {
+        CodecPropagator::new()
+    }
+}
+
+impl<T> std::fmt::Debug for CodecPropagator<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CodecPropagator")
+            .field("type", &std::any::type_name::<T>())
+            .finish()
+    }
+}
+
+impl<T: MetadataCodec> Propagator for CodecPropagator<T> {
+    fn inject(&self, context: &Context, metadata: &mut MetadataMap) {
+        let value = context
+            .get::<T>()
+            .and_then(T::encode)
+            .and_then(|value| AsciiMetadataValue::try_from(value).ok());
+
+        if let Some(value) = value {
+            metadata.insert(T::KEY, value);
+        }
+    }
+
+    fn extract(&self, context: Context, metadata: &MetadataMap) -> Context {
+        match decode_metadata(metadata, T::KEY).and_then(T::decode) {
+            Some(value) => context.with_value(value),
+            None => context,
+        }
+    }
+}
+
+/// 以`ContextKey`的名稱作為metadata key傳遞任意字串
+#[derive(Debug)]
+pub struct StringPropagator {
+    key: &'static ContextKey<String>,
+}
+
+impl StringPropagator {
+    /// `key`的名稱必須是合法的metadata key, 否則會panic
+    pub fn new(key: &'static ContextKey<String>) -> Self {
+        AsciiMetadataKey::from_static(key.name());
+        StringPropagator { key }
+    }
+}
+
+impl Propagator for StringPropagator {
+    fn inject(&self, context: &Context, metadata: &mut MetadataMap) {
+        let value = context
+            .get_keyed(self.key)
+            .and_then(|value| AsciiMetadataValue::try_from(value.as_str()).ok());
+
+        if let Some(value) = value {
+            metadata.insert(self.key.name(), value);
+        }
+    }
+
+    fn extract(&self, context: Context, metadata: &MetadataMap) -> Context {
+        match decode_metadata(metadata, self.key.name()) {
+            Some(value) => context.with_keyed_value(self.key, value.to_string()),
+            None => context,
+        }
+    }
+}
+
+fn decode_metadata<'a>(metadata: &'a MetadataMap, key: &str) -> Option<&'a str> {
+    metadata
+        .get(key)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+}
+
+/// 請求的識別碼, 對應`x-request-id`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestId(pub String);
+
+impl MetadataCodec for RequestId {
+    const KEY: &'static str = "x-request-id";
+
+    fn encode(&self) -> Option<String> {
+        Some(self.0.clone())
+    }
+
+    fn decode(value: &str) -> Option<Self> {
+        Some(RequestId(value.to_string()))
+    }
+}
+
+/// 租戶的識別碼, 對應`x-tenant-id`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TenantId(pub String);
+
+impl MetadataCodec for TenantId {
+    const KEY: &'static str = "x-tenant-id";
+
+    fn encode(&self) -> Option<String> {
+        Some(self.0.clone())
+    }
+
+    fn decode(value: &str) -> Option<Self> {
+        Some(TenantId(value.to_string()))
+    }
+}
+
+/// 發出請求的使用者, 對應`x-user-principal`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserPrincipal(pub String);
+
+impl MetadataCodec for UserPrincipal {
+    const KEY: &'static str = "x-user-principal";
+
+    fn encode(&self) -> Option<String> {
+        Some(self.0.clone())
+    }
+
+    fn decode(value: &str) -> Option<Self> {
+        Some(UserPrincipal(value.to_string()))
+    }
+}
+
+/// 呼叫下游服務時使用的憑證, 對應`authorization: Bearer <token>`
+#[derive(Clone, PartialEq, Eq)]
+pub struct AuthToken(pub String);
+
+impl std::fmt::Debug for AuthToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("AuthToken(***)")
+    }
+}
+
+impl MetadataCodec for AuthToken {
+    const KEY: &'static str = "authorization";
+
+    fn encode(&self) -> Option<String> {
+        Some(format!("Bearer {}", self.0))
+    }
+
+    fn decode(value: &str) -> Option<Self> {
+        let token = value
+            .strip_prefix("Bearer ")
+            .or_else(|| value.strip_prefix("bearer "))?;
+        Some(AuthToken(token.trim().to_string()))
+    }
+}
+
+/// 在`grpc-timeout`與Context的deadline之間轉換
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GrpcTimeoutPropagator;
+
+const GRPC_TIMEOUT: &str = "grpc-timeout";
+
+impl Propagator for GrpcTimeoutPropagator {
+    fn inject(&self, context: &Context, metadata: &mut MetadataMap) {
+        let Some(deadline) = context.deadline() else {
+            return;
+        };
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        if let Ok(value) = AsciiMetadataValue::try_from(encode_grpc_timeout(timeout)) {
+            metadata.insert(GRPC_TIMEOUT, value);
+        }
+    }
+
+    fn extract(&self, context: Context, metadata: &MetadataMap) -> Context {
+        match decode_metadata(metadata, GRPC_TIMEOUT).and_then(decode_grpc_timeout) {
+            Some(timeout) => context.with_timeout(timeout).0,
+            None => context,
+        }
+    }
+}
+
+/// Encodes with the most precise unit which fits into the 8 digits allowed by
+/// https://github.com/grpc/grpc/blob/master/doc/PROTOCOL-HTTP2.md
+fn encode_grpc_timeout(timeout: Duration) -> String {
+    const MAX: u128 = 99_999_999;
+    let nanos = timeout.as_nanos();
+    let units: [(u128, char); 6] = [
+        (1, 'n'),
+        (1_000, 'u'),
+        (1_000_000, 'm'),
+        (1_000_000_000, 'S'),
+        (60_000_000_000, 'M'),
+        (3_600_000_000_000, 'H'),
+    ];
+
+    units
+        .iter()
+        .find(|(scale, _)| nanos / scale <= MAX)
+        .map(|(scale, unit)| format!("{}{}", nanos.div_ceil(*scale).min(MAX), unit))
+        .unwrap_or_else(|| format!("{MAX}H"))
+}
+
+fn decode_grpc_timeout(value: &str) -> Option<Duration> {
+    let (digits, unit) = value.split_at(value.len().checked_sub(1)?);
+    if digits.is_empty() || digits.len() > 8 || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let value: u64 = digits.parse().ok()?;
+
+    match unit {
+        "H" => Some(Duration::from_secs(value * 3600)),
+        "M" => Some(Duration::from_secs(value * 60)),
+        "S" => Some(Duration::from_secs(value)),
+        "m" => Some(Duration::from_millis(value)),
+        "u" => Some(Duration::from_micros(value)),
+        "n" => Some(Duration::from_nanos(value)),
+        _ => None,
+    }
+}
+
+pub fn request_id() -> CodecPropagator<RequestId> {
+    CodecPropagator::new()
+}
+
+pub fn tenant_id() -> CodecPropagator<TenantId> {
+    CodecPropagator::new()
+}
+
+pub fn user_principal() -> CodecPropagator<UserPrincipal> {
+    CodecPropagator::new()
+}
+
+pub fn auth_token() -> CodecPropagator<AuthToken> {
+    CodecPropagator::new()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    static CHANNEL: ContextKey<String> = ContextKey::new("x-channel");
+
+    #[test]
+    fn inject_and_extract() {
+        let propagators: Vec<Box<dyn Propagator>> = vec![
+            Box::new(request_id()),
+            Box::new(tenant_id()),
+            Box::new(user_principal()),
+            Box::new(StringPropagator::new(&CHANNEL)),
+        ];
+
+        let cx = Context::new()
+            .with_value(RequestId("req-1".to_string()))
+            .with_value(TenantId("tenant-a".to_string()))
+            .with_keyed_value(&CHANNEL, "web".to_string());
+
+        let mut metadata = MetadataMap::new();
+        for propagator in &propagators {
+            propagator.inject(&cx, &mut metadata);
+        }
+        assert_eq!(metadata.get("x-request-id").unwrap(), "req-1");
+        assert_eq!(metadata.get("x-channel").unwrap(), "web");
+        assert!(metadata.get("x-user-principal").is_none());
+
+        let extracted = propagators
+            .iter()
+            .fold(Context::new(), |cx, p| p.extract(cx, &metadata));
+        assert_eq!(
+            extracted.get::<RequestId>(),
+            Some(&RequestId("req-1".to_string()))
+        );
+        assert_eq!(
+            extracted.get::<TenantId>(),
+            Some(&TenantId("tenant-a".to_string()))
+        );
+        assert_eq!(extracted.get::<UserPrincipal>(), None);
+        assert_eq!(
+            extracted.get_keyed(&CHANNEL).map(String::as_str),
+            Some("web")
+        );
+    }
+
+    #[test]
+    fn grpc_timeout() {
+        assert_eq!(encode_grpc_timeout(Duration::from_millis(1500)), "1500000u");
+        assert_eq!(encode_grpc_timeout(Duration::from_secs(3600)), "3600000m");
+        assert_eq!(
+            decode_grpc_timeout("1500000u"),
+            Some(Duration::from_millis(1500))
+        );
+        assert_eq!(decode_grpc_timeout("2H"), Some(Duration::from_secs(7200)));
+        assert_eq!(decode_grpc_timeout("123456789S"), None);
+        assert_eq!(decode_grpc_timeout("10x"), None);
+
+        let (cx, _) = Context::new().with_timeout(Duration::from_secs(10));
+        let mut metadata = MetadataMap::new();
+        GrpcTimeoutPropagator.inject(&cx, &mut metadata);
+
+        let deadline = GrpcTimeoutPropagator
+            .extract(Context::new(), &metadata)
+            .deadline()
+            .unwrap();
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        assert!(remaining > Duration::from_secs(9) && remaining <= Duration::from_secs(10));
+    }
+
+    #[test]
+    fn auth_token_round_trip() {
+        let mut metadata = MetadataMap::new();
+        let cx = Context::new().with_value(AuthToken("secret".to_string()));
+        auth_token().inject(&cx, &mut metadata);
+        assert_eq!(metadata.get("authorization").unwrap(), "Bearer secret");
+
+        let cx = auth_token().extract(Context::new(), &metadata);
+        assert_eq!(
+            cx.get::<AuthToken>(),
+            Some(&AuthToken("secret".to_string()))
+        );
+    }
+
+    #[test]
+    fn invalid_values_are_skipped() {
+        let cx = Context::new().with_value(RequestId("line\nbreak".to_string()));
+        let mut metadata = MetadataMap::new();
+        request_id().inject(&cx, &mut metadata);
+        assert!(metadata.is_empty());
+
+        metadata.insert("x-request-id", AsciiMetadataValue::from_static("  "));
+        let cx = request_id().extract(Context::new(), &metadata);
+        assert_eq!(cx.get::<RequestId>(), None);
+    }
+}
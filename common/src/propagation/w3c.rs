@@ -0,0 +1,733 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+use tonic::metadata::{AsciiMetadataValue, MetadataMap};
+
+use crate::context::Context;
+use crate::propagation::Propagator;
+
+const TRACEPARENT: &str = "traceparent";
+const TRACESTATE: &str = "tracestate";
+const BAGGAGE: &str = "baggage";
+
+/// Limits from https://www.w3.org/TR/trace-context/#tracestate-limits
+const TRACESTATE_MAX_MEMBERS: usize = 32;
+const TRACESTATE_MAX_LENGTH: usize = 512;
+const TRACESTATE_LARGE_MEMBER: usize = 128;
+
+/// Limits from https://www.w3.org/TR/baggage/#limits
+const BAGGAGE_MAX_MEMBERS: usize = 64;
+const BAGGAGE_MAX_LENGTH: usize = 8192;
+
+/// Everything outside of `baggage-octet`, plus `%` itself
+const BAGGAGE_VALUE: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b',')
+    .add(b';')
+    .add(b'\\')
+    .add(b'%');
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceContextError {
+    InvalidVersion,
+    InvalidFormat,
+    InvalidTraceId,
+    InvalidParentId,
+    InvalidTraceState,
+}
+
+impl std::fmt::Display for TraceContextError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TraceContextError::InvalidVersion => f.write_str("invalid traceparent version"),
+            TraceContextError::InvalidFormat => f.write_str("invalid traceparent format"),
+            TraceContextError::InvalidTraceId => f.write_str("invalid trace id"),
+            TraceContextError::InvalidParentId => f.write_str("invalid parent id"),
+            TraceContextError::InvalidTraceState => f.write_str("invalid tracestate"),
+        }
+    }
+}
+
+impl std::error::Error for TraceContextError {}
+
+/// W3C Trace Context, 對應`traceparent`與`tracestate`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceContext {
+    pub trace_id: [u8; 16],
+    pub parent_id: [u8; 8],
+    pub trace_flags: u8,
+    pub trace_state: TraceState,
+}
+
+impl TraceContext {
+    pub const FLAG_SAMPLED: u8 = 0x01;
+
+    /// 解析`traceparent`, `tracestate`不合法時會被忽略, 與規範相同
+    pub fn parse(traceparent: &str, tracestate: Option<&str>) -> Result<Self, TraceContextError> {
+        let traceparent = traceparent.trim();
+        let version = traceparent
+            .get(0..2)
+            .and_then(parse_hex::<1>)
+            .ok_or(TraceContextError::InvalidVersion)?[0];
+        if version == 0xff {
+            return Err(TraceContextError::InvalidVersion);
+        }
+        // Version 00 has exactly 55 characters, later versions may append fields
+        let length_ok = match version {
+            0 => traceparent.len() == 55,
+            _ => traceparent.len() == 55 || traceparent.as_bytes().get(55) == Some(&b'-'),
+        };
+        if !length_ok || !traceparent.is_char_boundary(55) {
+            return Err(TraceContextError::InvalidFormat);
+        }
+
+        let mut fields = traceparent[..55].split('-');
+        let (Some(_), Some(trace_id), Some(parent_id), Some(flags), None) = (
+            fields.next(),
+            fields.next(),
+            fields.next(),
+            fields.next(),
+            fields.next(),
+        ) else {
+            return Err(TraceContextError::InvalidFormat);
+        };
+
+        let trace_id = parse_hex::<16>(trace_id)
+            .filter(|id| id.iter().any(|b| *b != 0))
+            .ok_or(TraceContextError::InvalidTraceId)?;
+        let parent_id = parse_hex::<8>(parent_id)
+            .filter(|id| id.iter().any(|b| *b != 0))
+            .ok_or(TraceContextError::InvalidParentId)?;
+        let trace_flags = parse_hex::<1>(flags).ok_or(TraceContextError::InvalidFormat)?[0];
+
+        let trace_state = tracestate
+            .and_then(|value| TraceState::parse(value).ok())
+            .unwrap_or_default();
+
+        Ok(TraceContext {
+            trace_id,
+            parent_id,
+            trace_flags,
+            trace_state,
+        })
+    }
+
+    /// 以新的span id作為`parent_id`的TraceContext, 建立自己的span時以它取代Context中的值,
+    /// 下游收到的`parent_id`才會與記錄的span一致
+    pub fn child(&self) -> Self {
+        let mut parent_id = [0; 8];
+        while parent_id == [0; 8] {
+            parent_id = random_u64().to_be_bytes();
+        }
+
+        TraceContext {
+            parent_id,
+            ..self.clone()
+        }
+    }
+
+    pub fn is_sampled(&self) -> bool {
+        self.trace_flags & Self::FLAG_SAMPLED != 0
+    }
+
+    pub fn trace_id_hex(&self) -> String {
+        to_hex(&self.trace_id)
+    }
+
+    pub fn parent_id_hex(&self) -> String {
+        to_hex(&self.parent_id)
+    }
+
+    /// 以version `00`輸出`traceparent`
+    pub fn traceparent(&self) -> String {
+        format!(
+            "00-{}-{}-{:02x}",
+            self.trace_id_hex(),
+            self.parent_id_hex(),
+            self.trace_flags
+        )
+    }
+}
+
+/// `tracestate`中的vendor資料, 依照規範最多32個
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TraceState {
+    members: Vec<(String, String)>,
+}
+
+impl TraceState {
+    pub fn parse(value: &str) -> Result<Self, TraceContextError> {
+        let mut members: Vec<(String, String)> = Vec::new();
+
+        for member in value.split(',').map(|m| m.trim_matches([' ', '\t'])) {
+            if member.is_empty() {
+                continue;
+            }
+            let (key, value) = member
+                .split_once('=')
+                .filter(|(key, value)| valid_tracestate_key(key) && valid_tracestate_value(value))
+                .ok_or(TraceContextError::InvalidTraceState)?;
+            if members.iter().any(|(k, _)| k == key) {
+                return Err(TraceContextError::InvalidTraceState);
+            }
+            members.push((key.to_string(), value.to_string()));
+        }
+
+        if members.len() > TRACESTATE_MAX_MEMBERS {
+            return Err(TraceContextError::InvalidTraceState);
+        }
+        Ok(TraceState { members })
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.members
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// 新增或更新的值會移到最前面, 超過上限時移除最後一個
+    pub fn insert(
+        &mut self,
+        key: impl Into<String>,
+        value: impl Into<String>,
+    ) -> Result<(), TraceContextError> {
+        let (key, value) = (key.into(), value.into());
+        if !valid_tracestate_key(&key) || !valid_tracestate_value(&value) {
+            return Err(TraceContextError::InvalidTraceState);
+        }
+
+        self.members.retain(|(k, _)| *k != key);
+        self.members.insert(0, (key, value));
+        self.members.truncate(TRACESTATE_MAX_MEMBERS);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<String> {
+        let index = self.members.iter().position(|(k, _)| k == key)?;
+        Some(self.members.remove(index).1)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.members.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    /// 輸出`tracestate`, 超過512字元時先移除大於128字元的成員, 再從最後面開始移除
+    pub fn header(&self) -> Option<String> {
+        let mut members: Vec<String> = self
+            .members
+            .iter()
+            .map(|(k, v)| format!("{k}={v}"))
+            .collect();
+        let length = |members: &[String]| {
+            members.iter().map(String::len).sum::<usize>() + members.len().saturating_sub(1)
+        };
+
+        if length(&members) > TRACESTATE_MAX_LENGTH {
+            members.retain(|m| m.len() <= TRACESTATE_LARGE_MEMBER);
+        }
+        while length(&members) > TRACESTATE_MAX_LENGTH {
+            members.pop();
+        }
+
+        (!members.is_empty()).then(|| members.join(","))
+    }
+}
+
+fn valid_tracestate_key(key: &str) -> bool {
+    fn valid_chars(s: &str) -> bool {
+        s.bytes().all(|b| {
+            b.is_ascii_lowercase() || b.is_ascii_digit() || matches!(b, b'_' | b'-' | b'*' | b'/')
+        })
+    }
+
+    match key.split_once('@') {
+        // multi-tenant-key = tenant-id "@" system-id
+        Some((tenant, system)) => {
+            (1..=241).contains(&tenant.len())
+                && tenant
+                    .bytes()
+                    .next()
+                    .is_some_and(|b| b.is_ascii_lowercase() || b.is_ascii_digit())
+                && valid_chars(tenant)
+                && (1..=14).contains(&system.len())
+                && system
+                    .bytes()
+                    .next()
+                    .is_some_and(|b| b.is_ascii_lowercase())
+                && valid_chars(system)
+        }
+        // simple-key = lcalpha 0*255( lcalpha / DIGIT / "_" / "-"/ "*" / "/" )
+        None => {
+            (1..=256).contains(&key.len())
+                && key.bytes().next().is_some_and(|b| b.is_ascii_lowercase())
+                && valid_chars(key)
+        }
+    }
+}
+
+fn valid_tracestate_value(value: &str) -> bool {
+    // value = 0*255(chr) nblk-chr, where nblk-chr excludes "," and "="
+    (1..=256).contains(&value.len())
+        && !value.ends_with(' ')
+        && value
+            .bytes()
+            .all(|b| (0x20..=0x7e).contains(&b) && b != b',' && b != b'=')
+}
+
+/// W3C Baggage, 對應`baggage`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Baggage {
+    entries: Vec<BaggageEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BaggageEntry {
+    pub key: String,
+    pub value: String,
+    /// 未解析的property, 例如`ttl=10;secure`
+    pub properties: Option<String>,
+}
+
+impl Baggage {
+    pub fn new() -> Self {
+        Baggage::default()
+    }
+
+    /// 解析`baggage`, 不合法的成員會被略過, 超過64個成員或8192 bytes的部分會被捨棄
+    pub fn parse(value: &str) -> Self {
+        let mut baggage = Baggage::new();
+        let mut length = 0;
+
+        for member in value.split(',').map(|m| m.trim_matches([' ', '\t'])) {
+            if baggage.entries.len() >= BAGGAGE_MAX_MEMBERS {
+                break;
+            }
+            length += member.len() + usize::from(length > 0);
+            if length > BAGGAGE_MAX_LENGTH {
+                break;
+            }
+            if let Some(entry) = parse_baggage_member(member) {
+                baggage.entries.retain(|e| e.key != entry.key);
+                baggage.entries.push(entry);
+            }
+        }
+
+        baggage
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|e| e.key == key)
+            .map(|e| e.value.as_str())
+    }
+
+    pub fn insert(&mut self, key: impl Into<String>, value: impl Into<String>) -> bool {
+        self.insert_entry(BaggageEntry {
+            key: key.into(),
+            value: value.into(),
+            properties: None,
+        })
+    }
+
+    /// 加入一個成員, key不合法或超過成員上限時回傳`false`
+    pub fn insert_entry(&mut self, entry: BaggageEntry) -> bool {
+        if !valid_token(&entry.key) {
+            return false;
+        }
+        self.entries.retain(|e| e.key != entry.key);
+        if self.entries.len() >= BAGGAGE_MAX_MEMBERS {
+            return false;
+        }
+        self.entries.push(entry);
+        true
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<BaggageEntry> {
+        let index = self.entries.iter().position(|e| e.key == key)?;
+        Some(self.entries.remove(index))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &BaggageEntry> {
+        self.entries.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// 輸出`baggage`, 超過8192 bytes的成員會被捨棄
+    pub fn header(&self) -> Option<String> {
+        let mut header = String::new();
+
+        for entry in &self.entries {
+            let mut member = format!(
+                "{}={}",
+                entry.key,
+                utf8_percent_encode(&entry.value, BAGGAGE_VALUE)
+            );
+            if let Some(properties) = &entry.properties {
+                member.push(';');
+                member.push_str(properties);
+            }
+
+            let separator = usize::from(!header.is_empty());
+            if header.len() + separator + member.len() > BAGGAGE_MAX_LENGTH {
+                continue;
+            }
+            if separator == 1 {
+                header.push(',');
+            }
+            header.push_str(&member);
+        }
+
+        (!header.is_empty()).then_some(header)
+    }
+}
+
+fn parse_baggage_member(member: &str) -> Option<BaggageEntry> {
+    let (key_value, properties) = match member.split_once(';') {
+        Some((key_value, properties)) => (key_value, Some(properties.trim())),
+        None => (member, None),
+    };
+
+    let (key, value) = key_value.split_once('=')?;
+    let (key, value) = (key.trim(), value.trim());
+    if !valid_token(key) || !value.bytes().all(valid_baggage_octet) {
+        return None;
+    }
+    let value = percent_decode_str(value).decode_utf8().ok()?.into_owned();
+
+    Some(BaggageEntry {
+        key: key.to_string(),
+        value,
+        properties: properties.filter(|p| !p.is_empty()).map(str::to_string),
+    })
+}
+
+fn valid_token(key: &str) -> bool {
+    !key.is_empty()
+        && key.bytes().all(|b| {
+            b.is_ascii_alphanumeric()
+                || matches!(
+                    b,
+                    b'!' | b'#'
+                        | b'$'
+                        | b'%'
+                        | b'&'
+                        | b'\''
+                        | b'*'
+                        | b'+'
+                        | b'-'
+                        | b'.'
+                        | b'^'
+                        | b'_'
+                        | b'`'
+                        | b'|'
+                        | b'~'
+                )
+        })
+}
+
+fn valid_baggage_octet(b: u8) -> bool {
+    matches!(b, 0x21 | 0x23..=0x2b | 0x2d..=0x3a | 0x3c..=0x5b | 0x5d..=0x7e)
+}
+
+/// 在`traceparent`/`tracestate`與[`TraceContext`]之間轉換
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TraceContextPropagator;
+
+impl Propagator for TraceContextPropagator {
+    fn inject(&self, context: &Context, metadata: &mut MetadataMap) {
+        let Some(trace_context) = context.get::<TraceContext>() else {
+            return;
+        };
+
+        // Sent unchanged, a span id made up here would be recorded nowhere and
+        // leave the downstream spans without a parent
+        if let Ok(value) = AsciiMetadataValue::try_from(trace_context.traceparent()) {
+            metadata.insert(TRACEPARENT, value);
+        }
+        let trace_state = trace_context
+            .trace_state
+            .header()
+            .and_then(|value| AsciiMetadataValue::try_from(value).ok());
+        if let Some(value) = trace_state {
+            metadata.insert(TRACESTATE, value);
+        }
+    }
+
+    fn extract(&self, context: Context, metadata: &MetadataMap) -> Context {
+        let Some(traceparent) = metadata.get(TRACEPARENT).and_then(|v| v.to_str().ok()) else {
+            return context;
+        };
+        // Multiple tracestate headers are combined as a single list
+        let tracestate: Vec<&str> = metadata
+            .get_all(TRACESTATE)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .collect();
+        let tracestate = (!tracestate.is_empty()).then(|| tracestate.join(","));
+
+        match TraceContext::parse(traceparent, tracestate.as_deref()) {
+            Ok(trace_context) => context.with_value(trace_context),
+            Err(_) => context,
+        }
+    }
+}
+
+/// 在`baggage`與[`Baggage`]之間轉換
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BaggagePropagator;
+
+impl Propagator for BaggagePropagator {
+    fn inject(&self, context: &Context, metadata: &mut MetadataMap) {
+        let value = context
+            .get::<Baggage>()
+            .and_then(Baggage::header)
+            .and_then(|value| AsciiMetadataValue::try_from(value).ok());
+
+        if let Some(value) = value {
+            metadata.insert(BAGGAGE, value);
+        }
+    }
+
+    fn extract(&self, context: Context, metadata: &MetadataMap) -> Context {
+        let values: Vec<&str> = metadata
+            .get_all(BAGGAGE)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .collect();
+        let baggage = Baggage::parse(&values.join(","));
+
+        if baggage.is_empty() {
+            context
+        } else {
+            context.with_value(baggage)
+        }
+    }
+}
+
+fn parse_hex<const N: usize>(value: &str) -> Option<[u8; N]> {
+    // Only lowercase hex is valid in a traceparent
+    fn nibble(b: u8) -> Option<u8> {
+        match b {
+            b'0'..=b'9' => Some(b - b'0'),
+            b'a'..=b'f' => Some(b - b'a' + 10),
+            _ => None,
+        }
+    }
+
+    let bytes = value.as_bytes();
+    if bytes.len() != N * 2 {
+        return None;
+    }
+    let mut out = [0; N];
+    for (i, pair) in bytes.chunks_exact(2).enumerate() {
+        out[i] = nibble(pair[0])? << 4 | nibble(pair[1])?;
+    }
+    Some(out)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn random_u64() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const TRACEPARENT_VALUE: &str = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+
+    #[test]
+    fn parse_traceparent() {
+        let trace_context = TraceContext::parse(
+            TRACEPARENT_VALUE,
+            Some("congo=t61rcWkgMzE, rojo=00f067aa0ba902b7"),
+        )
+        .unwrap();
+
+        assert_eq!(
+            trace_context.trace_id_hex(),
+            "0af7651916cd43dd8448eb211c80319c"
+        );
+        assert_eq!(trace_context.parent_id_hex(), "b7ad6b7169203331");
+        assert!(trace_context.is_sampled());
+        assert_eq!(
+            trace_context.trace_state.get("rojo"),
+            Some("00f067aa0ba902b7")
+        );
+        assert_eq!(trace_context.traceparent(), TRACEPARENT_VALUE);
+        assert_eq!(
+            trace_context.trace_state.header().as_deref(),
+            Some("congo=t61rcWkgMzE,rojo=00f067aa0ba902b7")
+        );
+
+        let child = trace_context.child();
+        assert_eq!(child.trace_id, trace_context.trace_id);
+        assert_ne!(child.parent_id, trace_context.parent_id);
+    }
+
+    #[test]
+    fn reject_invalid_traceparent() {
+        let invalid = [
+            (
+                "ff-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+                TraceContextError::InvalidVersion,
+            ),
+            (
+                "00-0AF7651916CD43DD8448EB211C80319C-b7ad6b7169203331-01",
+                TraceContextError::InvalidTraceId,
+            ),
+            (
+                "00-00000000000000000000000000000000-b7ad6b7169203331-01",
+                TraceContextError::InvalidTraceId,
+            ),
+            (
+                "00-0af7651916cd43dd8448eb211c80319c-0000000000000000-01",
+                TraceContextError::InvalidParentId,
+            ),
+            (
+                "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01-extra",
+                TraceContextError::InvalidFormat,
+            ),
+            (
+                "00-0af7651916cd43dd8448eb211c80319c-b7ad6b716920333-001",
+                TraceContextError::InvalidParentId,
+            ),
+        ];
+        for (value, error) in invalid {
+            assert_eq!(TraceContext::parse(value, None), Err(error), "{value}");
+        }
+
+        // Later versions may append fields
+        let future = "01-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01-extra";
+        assert!(TraceContext::parse(future, None).is_ok());
+    }
+
+    #[test]
+    fn invalid_tracestate_is_dropped() {
+        let duplicated = TraceContext::parse(TRACEPARENT_VALUE, Some("a=1,a=2")).unwrap();
+        assert!(duplicated.trace_state.is_empty());
+
+        let too_many = (0..33)
+            .map(|i| format!("k{i}=v"))
+            .collect::<Vec<_>>()
+            .join(",");
+        assert!(TraceState::parse(&too_many).is_err());
+        assert!(TraceState::parse("Upper=1").is_err());
+        assert!(TraceState::parse("tenant@system=1").is_ok());
+
+        let mut trace_state = TraceState::parse("a=1,b=2").unwrap();
+        trace_state.insert("b", "3").unwrap();
+        assert_eq!(trace_state.header().as_deref(), Some("b=3,a=1"));
+    }
+
+    #[test]
+    fn tracestate_header_is_truncated() {
+        let mut trace_state = TraceState::default();
+        trace_state.insert("large", "x".repeat(200)).unwrap();
+        for i in 0..30 {
+            trace_state.insert(format!("k{i}"), "v".repeat(20)).unwrap();
+        }
+
+        let header = trace_state.header().unwrap();
+        assert!(header.len() <= TRACESTATE_MAX_LENGTH);
+        assert!(!header.contains("large="));
+        assert!(header.starts_with("k29="));
+    }
+
+    #[test]
+    fn baggage_round_trip() {
+        let baggage = Baggage::parse(
+            "userId=alice, serverNode = DF%2028 ,isProduction=false;ttl=10, invalid key=1",
+        );
+
+        assert_eq!(baggage.len(), 3);
+        assert_eq!(baggage.get("serverNode"), Some("DF 28"));
+        assert_eq!(
+            baggage.iter().last().unwrap().properties.as_deref(),
+            Some("ttl=10")
+        );
+        assert_eq!(
+            baggage.header().as_deref(),
+            Some("userId=alice,serverNode=DF%2028,isProduction=false;ttl=10")
+        );
+
+        let mut baggage = Baggage::new();
+        assert!(baggage.insert("name", "小明, 100%"));
+        assert!(!baggage.insert("bad key", "1"));
+        let parsed = Baggage::parse(&baggage.header().unwrap());
+        assert_eq!(parsed.get("name"), Some("小明, 100%"));
+    }
+
+    #[test]
+    fn baggage_limits() {
+        let members = (0..100)
+            .map(|i| format!("k{i}=v"))
+            .collect::<Vec<_>>()
+            .join(",");
+        assert_eq!(Baggage::parse(&members).len(), BAGGAGE_MAX_MEMBERS);
+
+        let large = format!("a={},b=1", "x".repeat(BAGGAGE_MAX_LENGTH));
+        let baggage = Baggage::parse(&large);
+        assert_eq!(baggage.len(), 0);
+
+        let mut baggage = Baggage::new();
+        baggage.insert("a", "x".repeat(BAGGAGE_MAX_LENGTH));
+        baggage.insert("b", "1");
+        assert_eq!(baggage.header().as_deref(), Some("b=1"));
+    }
+
+    #[test]
+    fn child_span_is_propagated() {
+        let incoming = TraceContext::parse(TRACEPARENT_VALUE, None).unwrap();
+        let child = incoming.child();
+        let cx = Context::new().with_value(child.clone());
+
+        let mut metadata = MetadataMap::new();
+        TraceContextPropagator.inject(&cx, &mut metadata);
+        let injected = TraceContextPropagator.extract(Context::new(), &metadata);
+
+        assert_eq!(injected.get::<TraceContext>(), Some(&child));
+        assert_eq!(child.trace_id, incoming.trace_id);
+        assert_ne!(child.parent_id, incoming.parent_id);
+    }
+
+    #[test]
+    fn propagate_trace_context_and_baggage() {
+        let mut baggage = Baggage::new();
+        baggage.insert("tenant", "a");
+        let cx = Context::new()
+            .with_value(TraceContext::parse(TRACEPARENT_VALUE, Some("rojo=1")).unwrap())
+            .with_value(baggage.clone());
+
+        let mut metadata = MetadataMap::new();
+        TraceContextPropagator.inject(&cx, &mut metadata);
+        BaggagePropagator.inject(&cx, &mut metadata);
+        assert_eq!(metadata.get(TRACESTATE).unwrap(), "rojo=1");
+        assert_eq!(metadata.get(BAGGAGE).unwrap(), "tenant=a");
+
+        let extracted = BaggagePropagator.extract(
+            TraceContextPropagator.extract(Context::new(), &metadata),
+            &metadata,
+        );
+        assert_eq!(extracted.get::<TraceContext>(), cx.get::<TraceContext>());
+        assert_eq!(extracted.get::<Baggage>(), Some(&baggage));
+    }
+}
@@ -0,0 +1,203 @@
+use std::future::Future;
+
+use tokio::task::{AbortHandle, JoinError, JoinHandle};
+
+use crate::context::Context;
+use crate::with_context::FutureExt;
+
+/// 與`tokio::spawn`相同, 但新的task會沿用呼叫時的[`Context::current`]
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    tokio::spawn(future.with_current_context())
+}
+
+/// 與`tokio::task::spawn_blocking`相同, 但closure執行期間會attach呼叫時的[`Context::current`]
+pub fn spawn_blocking<F, R>(f: F) -> JoinHandle<R>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    tokio::task::spawn_blocking(with_current_context_blocking(f))
+}
+
+/// 與`tokio::task::spawn_local`相同, 但新的task會沿用呼叫時的[`Context::current`]
+pub fn spawn_local<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + 'static,
+    F::Output: 'static,
+{
+    tokio::task::spawn_local(future.with_current_context())
+}
+
+fn with_current_context_blocking<F, R>(f: F) -> impl FnOnce() -> R + Send + 'static
+where
+    F: FnOnce() -> R + Send + 'static,
+{
+    let context = Context::current();
+    move || {
+        let _guard = context.attach();
+        f()
+    }
+}
+
+/// 會把[`Context::current`]傳遞給每個task的`tokio::task::JoinSet`
+pub struct JoinSet<T> {
+    inner: tokio::task::JoinSet<T>,
+}
+
+impl<T: 'static> JoinSet<T> {
+    pub fn new() -> Self {
+        JoinSet {
+            inner: tokio::task::JoinSet::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    pub fn spawn<F>(&mut self, task: F) -> AbortHandle
+    where
+        F: Future<Output = T> + Send + 'static,
+        T: Send,
+    {
+        self.inner.spawn(task.with_current_context())
+    }
+
+    pub fn spawn_blocking<F>(&mut self, f: F) -> AbortHandle
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send,
+    {
+        self.inner.spawn_blocking(with_current_context_blocking(f))
+    }
+
+    pub fn spawn_local<F>(&mut self, task: F) -> AbortHandle
+    where
+        F: Future<Output = T> + 'static,
+    {
+        self.inner.spawn_local(task.with_current_context())
+    }
+
+    /// 以指定的`context`取代[`Context::current`]來執行task
+    pub fn spawn_with_context<F>(&mut self, task: F, context: Context) -> AbortHandle
+    where
+        F: Future<Output = T> + Send + 'static,
+        T: Send,
+    {
+        self.inner.spawn(task.with_context(context))
+    }
+
+    pub async fn join_next(&mut self) -> Option<Result<T, JoinError>> {
+        self.inner.join_next().await
+    }
+
+    pub fn try_join_next(&mut self) -> Option<Result<T, JoinError>> {
+        self.inner.try_join_next()
+    }
+
+    pub async fn shutdown(&mut self) {
+        self.inner.shutdown().await
+    }
+
+    pub fn abort_all(&mut self) {
+        self.inner.abort_all()
+    }
+
+    pub fn detach_all(&mut self) {
+        self.inner.detach_all()
+    }
+}
+
+impl<T: 'static> Default for JoinSet<T> {
+    fn default() -> Self {
+        JoinSet::new()
+    }
+}
+
+impl<T> std::fmt::Debug for JoinSet<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("JoinSet")
+            .field("len", &self.inner.len())
+            .finish()
+    }
+}
+
+impl<T, F> Extend<F> for JoinSet<T>
+where
+    F: Future<Output = T> + Send + 'static,
+    T: Send + 'static,
+{
+    fn extend<I: IntoIterator<Item = F>>(&mut self, iter: I) {
+        for task in iter {
+            self.spawn(task);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    struct RequestId(&'static str);
+
+    fn request_id() -> Option<&'static str> {
+        Context::map_current(|cx| cx.get::<RequestId>().map(|id| id.0))
+    }
+
+    #[tokio::test]
+    async fn spawn_inherits_current_context() {
+        let _guard = Context::new().with_value(RequestId("spawn")).attach();
+
+        let handle = spawn(async { request_id() });
+        let blocking = spawn_blocking(request_id);
+        drop(_guard);
+
+        assert_eq!(handle.await.unwrap(), Some("spawn"));
+        assert_eq!(blocking.await.unwrap(), Some("spawn"));
+        assert_eq!(tokio::spawn(async { request_id() }).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn spawn_local_inherits_current_context() {
+        let local = tokio::task::LocalSet::new();
+        local
+            .run_until(async {
+                let handle = {
+                    let _guard = Context::new().with_value(RequestId("local")).attach();
+                    spawn_local(async { request_id() })
+                };
+                assert_eq!(handle.await.unwrap(), Some("local"));
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn join_set_inherits_current_context() {
+        let mut set = JoinSet::new();
+        {
+            let _guard = Context::new().with_value(RequestId("set")).attach();
+            set.spawn(async { request_id() });
+            set.spawn_blocking(request_id);
+        }
+        set.spawn_with_context(
+            async { request_id() },
+            Context::new().with_value(RequestId("explicit")),
+        );
+
+        let mut ids = Vec::new();
+        while let Some(id) = set.join_next().await {
+            ids.push(id.unwrap().unwrap());
+        }
+        ids.sort();
+        assert_eq!(ids, vec!["explicit", "set", "set"]);
+    }
+}
@@ -1,6 +1,8 @@
+use crate::cancel::CancelReason;
 use crate::context::Context;
 use futures_core::stream::Stream;
 use futures_sink::Sink;
+use std::future::Future;
 use std::pin::Pin;
 use std::task::Context as TaskContext;
 use std::task::Poll;
@@ -17,6 +19,16 @@ pin_project! {
     }
 }
 
+impl<T> WithContext<T> {
+    pub fn context(&self) -> &Context {
+        &self.context
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
 impl<T: std::future::Future> std::future::Future for WithContext<T> {
     type Output = T::Output;
 
@@ -25,8 +37,8 @@ impl<T: std::future::Future> std::future::Future for WithContext<T> {
         cx: &mut TaskContext<'_>,
     ) -> std::task::Poll<Self::Output> {
         let this = self.project();
-        let _guard = this.context.clone().attach();
-        this.inner.poll(cx)
+        let inner = this.inner;
+        this.context.enter(|| inner.poll(cx))
     }
 }
 
@@ -38,8 +50,8 @@ impl<T: Stream> Stream for WithContext<T> {
         cx: &mut TaskContext<'_>,
     ) -> std::task::Poll<Option<Self::Item>> {
         let this = self.project();
-        let _guard = this.context.clone().attach();
-        this.inner.poll_next(cx)
+        let inner = this.inner;
+        this.context.enter(|| inner.poll_next(cx))
     }
 }
 
@@ -51,26 +63,89 @@ where
 
     fn poll_ready(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Result<(), Self::Error>> {
         let this = self.project();
-        let _guard = this.context.clone().attach();
-        T::poll_ready(this.inner, cx)
+        let inner = this.inner;
+        this.context.enter(|| T::poll_ready(inner, cx))
     }
 
     fn start_send(self: Pin<&mut Self>, item: I) -> Result<(), Self::Error> {
         let this = self.project();
-        let _guard = this.context.clone().attach();
-        T::start_send(this.inner, item)
+        let inner = this.inner;
+        this.context.enter(|| T::start_send(inner, item))
     }
 
     fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Result<(), Self::Error>> {
         let this = self.project();
-        let _guard = this.context.clone().attach();
-        T::poll_flush(this.inner, cx)
+        let inner = this.inner;
+        this.context.enter(|| T::poll_flush(inner, cx))
     }
 
     fn poll_close(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Result<(), Self::Error>> {
         let this = self.project();
-        let _guard = this.context.clone().attach();
-        T::poll_close(this.inner, cx)
+        let inner = this.inner;
+        this.context.enter(|| T::poll_close(inner, cx))
+    }
+}
+
+impl<T: http_body::Body> http_body::Body for WithContext<T> {
+    type Data = T::Data;
+    type Error = T::Error;
+
+    fn poll_data(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let this = self.project();
+        let inner = this.inner;
+        this.context.enter(|| inner.poll_data(cx))
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<Result<Option<http::HeaderMap>, Self::Error>> {
+        let this = self.project();
+        let inner = this.inner;
+        this.context.enter(|| inner.poll_trailers(cx))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> http_body::SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+pin_project! {
+    ///在`Context`被取消時提早結束的`WithContext`
+    pub struct UntilCancelled<T> {
+        #[pin]
+        inner: WithContext<T>,
+        cancelled: Pin<Box<dyn Future<Output = ()> + Send>>,
+    }
+}
+
+impl<T: Future> Future for UntilCancelled<T> {
+    type Output = Result<T::Output, CancelReason>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Self::Output> {
+        let mut this = self.project();
+        if let Poll::Ready(output) = this.inner.as_mut().poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+
+        match this.cancelled.as_mut().poll(cx) {
+            Poll::Ready(()) => {
+                let reason = this
+                    .inner
+                    .context
+                    .cancel_reason()
+                    .unwrap_or(CancelReason::Cancelled);
+                Poll::Ready(Err(reason))
+            }
+            Poll::Pending => Poll::Pending,
+        }
     }
 }
 
@@ -86,6 +161,15 @@ pub trait FutureExt: Sized {
         let context = Context::current();
         self.with_context(context)
     }
+
+    /// 與`with_context`相同, 但在`context`被取消或超過deadline時回傳`Err`並丟棄內部的future
+    fn until_cancelled(self, context: Context) -> UntilCancelled<Self> {
+        let cancelled = Box::pin(context.cancelled());
+        UntilCancelled {
+            inner: self.with_context(context),
+            cancelled,
+        }
+    }
 }
 
 impl<T: Sized> FutureExt for T {}
@@ -1,6 +1,8 @@
 use api::test::test_service_server::TestServiceServer;
 use common::context::Context;
+use common::database::Backoff;
 use kgs_tracing::{info, tracing};
+use std::time::Duration;
 use tokio;
 
 mod entity;
@@ -14,13 +16,28 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .db_name("test")
         .db_password("admin")
         .db_user("admin")
-        .build()
-        .await;
+        // Wait for Postgres at boot instead of exiting
+        .connect_retry(
+            Backoff::Exponential {
+                initial: Duration::from_millis(500),
+                max: Duration::from_secs(10),
+            },
+            Duration::from_secs(60),
+        )
+        .try_build()
+        .await?;
 
     let cx = Context::current().with_value(db);
 
     tonic::transport::Server::builder()
-        .layer(common::context_middleware::ContextHolder::new(cx))
+        .layer(
+            common::context_middleware::ContextHolder::new(cx)
+                .with_propagator(common::propagation::request_id())
+                .with_propagator(common::propagation::tenant_id())
+                .with_propagator(common::propagation::GrpcTimeoutPropagator)
+                .with_propagator(common::propagation::TraceContextPropagator)
+                .with_propagator(common::propagation::BaggagePropagator),
+        )
         .layer(kgs_tracing::middlewares::tonic::root_span_builder())
         .layer(kgs_tracing::middlewares::tonic::TracingRecord::default())
         .add_service(TestServiceServer::new(service::TestService::default()))
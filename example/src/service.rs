@@ -1,6 +1,9 @@
 use crate::entity;
 use common::with_context::FutureExt;
-use common::{database::Database, db_impl::SeaOrmPostgres};
+use common::{
+    database::Database,
+    db_impl::{ContextConnection, SeaOrmPostgres},
+};
 use kgs_tracing::tracing;
 use macros::transactional;
 use sea_orm::{ActiveModelTrait, DatabaseTransaction, Set};
@@ -89,23 +92,19 @@ async fn save_msg_1(msg: String) -> Result<String, tonic::Status> {
     Ok(format!("{}", name))
 }
 
+fn db_error(error: sea_orm::DbErr) -> tonic::Status {
+    tonic::Status::internal(error.to_string())
+}
+
 #[tracing::instrument]
-#[transactional(SeaOrmPostgres)]
+#[transactional(SeaOrmPostgres, map_err = db_error)]
 async fn save_msg_2(msg: String) -> Result<String, tonic::Status> {
-    // Get the context
-    let cx = Context::current();
-
-    // Create a transaction
-    let txn = cx
-        .get::<DatabaseTransaction>()
-        .expect("the DB struct `SeaPostgres` not found");
-
-    // Insert a new record
+    // Insert a new record with the transaction in `Context::current()`
     let entity = entity::hello::ActiveModel {
         name: Set(msg),
         ..Default::default()
     }
-    .insert(txn)
+    .insert(&ContextConnection)
     .await
     .expect("Failed to insert");
 
@@ -119,22 +118,14 @@ async fn save_msg_2(msg: String) -> Result<String, tonic::Status> {
 
 #[tracing::instrument]
 async fn update_msg(entity: entity::hello::Model) -> Result<String, sea_orm::DbErr> {
-    // Get the context
-    let cx = Context::current();
-
-    // Get the sea_orm database implementation
-    let txn = cx
-        .get::<DatabaseTransaction>()
-        .expect("the DB struct `SeaPostgres` not found");
-
-    // Insert a new record
+    // Uses the transaction of the caller, or the pool when called outside of a transaction
     let active_model = entity::hello::ActiveModel {
         id: Set(entity.id),
         name: Set("Updated".to_string()),
         ..Default::default()
     };
 
-    let entity = active_model.update(txn).await?;
+    let entity = active_model.update(&ContextConnection).await?;
 
     // Return the response
     Ok(format!("Saved: {},  id: {}", entity.name, entity.id))
@@ -4,25 +4,251 @@ use syn::{
     parse::{Parse, ParseStream},
     parse_macro_input,
     punctuated::Punctuated,
-    Ident, ItemFn, ReturnType, Token,
+    Expr, ExprLit, FnArg, Ident, ItemFn, Lit, Pat, ReturnType, Token, Type,
 };
 
-struct DatabaseTypes {
-    types: Punctuated<Ident, Token![,]>,
+enum TransactionalArg {
+    Database(Type),
+    Flag(Ident),
+    Option(Ident, Expr),
 }
 
-impl Parse for DatabaseTypes {
+const FLAGS: &[&str] = &["read_only", "deferrable"];
+
+impl Parse for TransactionalArg {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        if input.peek(Ident) && input.peek2(Token![=]) {
+            let ident: Ident = input.parse()?;
+            input.parse::<Token![=]>()?;
+            return Ok(TransactionalArg::Option(ident, input.parse()?));
+        }
+        let ty: Type = input.parse()?;
+        if let Type::Path(path) = &ty {
+            if let Some(ident) = path.path.get_ident() {
+                if FLAGS.iter().any(|flag| ident == flag) {
+                    return Ok(TransactionalArg::Flag(ident.clone()));
+                }
+            }
+        }
+        Ok(TransactionalArg::Database(ty))
+    }
+}
+
+/// `#[transactional(DbA, DbB<Marker>, propagation = "requires_new", isolation = "serializable", read_only, retry = 3, map_err = path)]`
+struct TransactionalArgs {
+    types: Vec<Type>,
+    propagation: Ident,
+    options: proc_macro2::TokenStream,
+    retry: Option<proc_macro2::TokenStream>,
+    map_err: Option<Expr>,
+}
+
+impl TransactionalArgs {
+    /// Converts a `DatabaseError` into the error type of the function
+    fn convert_error(&self, error: proc_macro2::TokenStream) -> proc_macro2::TokenStream {
+        match &self.map_err {
+            Some(map_err) => quote! { (#map_err)(#error) },
+            None => quote! { ::std::convert::From::from(#error) },
+        }
+    }
+
+    /// Declares `commit`, which commits every database together, with two-phase
+    /// commit when they all support it. With `retry` the errors of the databases
+    /// are recorded in the guard before they are converted
+    fn two_phase_commit(&self) -> proc_macro2::TokenStream {
+        let convert = self.convert_error(quote! { e });
+        let push = self.types.iter().enumerate().map(|(i, db_type)| {
+            let scope_var = format_ident!("scope_{}", i);
+            if self.retry.is_some() {
+                quote! {
+                    commit.push::<#db_type>(#scope_var, {
+                        let retry = ::std::sync::Arc::clone(guard.retry_tracker());
+                        move |e| {
+                            retry.record(&e);
+                            #convert
+                        }
+                    });
+                }
+            } else {
+                quote! { commit.push::<#db_type>(#scope_var, |e| #convert); }
+            }
+        });
+        quote! {
+            let mut commit = common::database::TwoPhaseCommit::new();
+            #(#push)*
+        }
+    }
+}
+
+impl Parse for TransactionalArgs {
     fn parse(input: ParseStream) -> syn::Result<Self> {
-        Ok(DatabaseTypes {
-            types: Punctuated::parse_terminated(input)?,
+        let args = Punctuated::<TransactionalArg, Token![,]>::parse_terminated(input)?;
+
+        let mut types = Vec::new();
+        let mut propagation = None;
+        let mut isolation = None;
+        let mut read_only = None;
+        let mut deferrable = None;
+        let mut retries = None;
+        let mut backoff = None;
+        let mut map_err = None;
+        for arg in args {
+            match arg {
+                TransactionalArg::Flag(flag) if flag == "read_only" => {
+                    set_once(&mut read_only, &flag, ())?
+                }
+                TransactionalArg::Flag(flag) => set_once(&mut deferrable, &flag, ())?,
+                TransactionalArg::Database(ty) => types.push(ty),
+                TransactionalArg::Option(name, value) => match name.to_string().as_str() {
+                    "propagation" => {
+                        let variant = parse_variant(&value, PROPAGATIONS)?;
+                        set_once(&mut propagation, &name, variant)?
+                    }
+                    "isolation" => {
+                        let variant = parse_variant(&value, ISOLATION_LEVELS)?;
+                        set_once(&mut isolation, &name, variant)?
+                    }
+                    "retry" => {
+                        let Lit::Int(value) = expect_lit(&value)? else {
+                            return Err(syn::Error::new_spanned(value, "expected an integer"));
+                        };
+                        set_once(&mut retries, &name, value.base10_parse::<u32>()?)?
+                    }
+                    "backoff" => {
+                        let value = parse_backoff(&value)?;
+                        set_once(&mut backoff, &name, (name.clone(), value))?
+                    }
+                    "map_err" => set_once(&mut map_err, &name, value)?,
+                    _ => {
+                        return Err(syn::Error::new(
+                            name.span(),
+                            format!("unknown option `{}`", name),
+                        ))
+                    }
+                },
+            }
+        }
+
+        let mut options = quote! { common::database::TransactionOptions::new() };
+        if let Some(isolation) = isolation {
+            options.extend(quote! { .isolation(common::database::IsolationLevel::#isolation) });
+        }
+        if read_only.is_some() {
+            options.extend(quote! { .read_only() });
+        }
+        if deferrable.is_some() {
+            options.extend(quote! { .deferrable() });
+        }
+
+        let retry = match (retries, backoff) {
+            (Some(retries), backoff) => {
+                let backoff = backoff.map_or_else(
+                    || quote! { common::database::Backoff::exponential() },
+                    |(_, backoff)| backoff,
+                );
+                Some(quote! { common::database::RetryPolicy::new(#retries, #backoff) })
+            }
+            (None, Some((name, _))) => {
+                return Err(syn::Error::new(name.span(), "`backoff` requires `retry`"));
+            }
+            (None, None) => None,
+        };
+
+        Ok(TransactionalArgs {
+            types,
+            propagation: propagation
+                .unwrap_or_else(|| Ident::new("Required", proc_macro2::Span::call_site())),
+            options,
+            retry,
+            map_err,
         })
     }
 }
 
+fn set_once<T>(slot: &mut Option<T>, name: &Ident, value: T) -> syn::Result<()> {
+    if slot.is_some() {
+        return Err(syn::Error::new(
+            name.span(),
+            format!("duplicated `{}`", name),
+        ));
+    }
+    *slot = Some(value);
+    Ok(())
+}
+
+const PROPAGATIONS: &[(&str, &str)] = &[
+    ("required", "Required"),
+    ("requires_new", "RequiresNew"),
+    ("nested", "Nested"),
+    ("supports", "Supports"),
+    ("mandatory", "Mandatory"),
+    ("never", "Never"),
+];
+
+const ISOLATION_LEVELS: &[(&str, &str)] = &[
+    ("read_uncommitted", "ReadUncommitted"),
+    ("read_committed", "ReadCommitted"),
+    ("repeatable_read", "RepeatableRead"),
+    ("serializable", "Serializable"),
+];
+
+const BACKOFFS: &[(&str, &str)] = &[
+    ("none", "None"),
+    ("fixed", "Fixed"),
+    ("exponential", "Exponential"),
+];
+
+fn parse_backoff(value: &Expr) -> syn::Result<proc_macro2::TokenStream> {
+    let variant = parse_variant(value, BACKOFFS)?;
+    Ok(if variant == "None" {
+        quote! { common::database::Backoff::None }
+    } else {
+        let constructor = Ident::new(&variant.to_string().to_lowercase(), variant.span());
+        quote! { common::database::Backoff::#constructor() }
+    })
+}
+
+fn expect_lit(value: &Expr) -> syn::Result<&Lit> {
+    match value {
+        Expr::Lit(ExprLit { lit, .. }) => Ok(lit),
+        _ => Err(syn::Error::new_spanned(value, "expected a literal")),
+    }
+}
+
+/// Maps the string value of an option to the name of the enum variant
+fn parse_variant(value: &Expr, variants: &[(&str, &str)]) -> syn::Result<Ident> {
+    let Lit::Str(value) = expect_lit(value)? else {
+        return Err(syn::Error::new_spanned(value, "expected a string"));
+    };
+    let text = value.value();
+    match variants.iter().find(|(name, _)| *name == text) {
+        Some((_, variant)) => Ok(Ident::new(variant, value.span())),
+        None => {
+            let expected: Vec<_> = variants
+                .iter()
+                .map(|(name, _)| format!("`{}`", name))
+                .collect();
+            Err(syn::Error::new(
+                value.span(),
+                format!(
+                    "unknown value `{}`, expected one of {}",
+                    text,
+                    expected.join(", ")
+                ),
+            ))
+        }
+    }
+}
+
+/// 以transaction執行函式, 成功時commit, 失敗時rollback
+///
+/// 函式本體中的`cx`是加入了transaction的Context, 與本體中的`Context::current()`相同
 #[proc_macro_attribute]
 pub fn transactional(attr: TokenStream, item: TokenStream) -> TokenStream {
     let input = parse_macro_input!(item as ItemFn);
-    let db_types = parse_macro_input!(attr as DatabaseTypes);
+    let db_types = parse_macro_input!(attr as TransactionalArgs);
+    let propagation = &db_types.propagation;
+    let options = &db_types.options;
 
     let fn_name = &input.sig.ident;
     let fn_args = &input.sig.inputs;
@@ -36,52 +262,178 @@ pub fn transactional(attr: TokenStream, item: TokenStream) -> TokenStream {
         ReturnType::Type(_, ty) => quote! { #ty },
     };
 
-    let db_setup = db_types.types.iter().enumerate().map(|(i, db_type)| {
-        let db_var = format_ident!("db_{}", i);
-        let txn_var = format_ident!("txn_{}", i);
-        quote! {
-            let #db_var = cx.get::<#db_type>().expect(&format!("the DB struct `{}` not found", stringify!(#db_type)));
-            let #txn_var = #db_var.create_transaction().await.expect(&format!("Failed to create transaction for {}", stringify!(#db_type)));
-        }
-    });
+    // Every database is looked up before any transaction is begun, so a missing
+    // one leaves nothing to roll back
+    let db_check: Vec<_> = db_types
+        .types
+        .iter()
+        .map(|db_type| {
+            let not_found = db_types.convert_error(quote! {
+                <#db_type as common::database::Database>::DatabaseError::from(
+                    common::database::TransactionError::DatabaseNotFound(stringify!(#db_type)),
+                )
+            });
+            quote! {
+                if cx.get::<#db_type>().is_none() {
+                    return Err(#not_found);
+                }
+            }
+        })
+        .collect();
 
-    let cx_setup = db_types.types.iter().enumerate().map(|(i, _)| {
-        let txn_var = format_ident!("txn_{}", i);
-        quote! { .with_value(#txn_var) }
-    });
+    // The guard rolls the transactions back when beginning one of them fails,
+    // the body panics or the whole future is dropped before the commit finishes
+    let begin_failed = db_types.convert_error(quote! { e });
+    let db_setup: Vec<_> = db_types
+        .types
+        .iter()
+        .enumerate()
+        .map(|(i, db_type)| {
+            let scope_var = format_ident!("scope_{}", i);
+            quote! {
+                let #scope_var = match guard
+                    .begin::<#db_type>(common::database::Propagation::#propagation, #options)
+                    .await
+                {
+                    Ok(scope) => scope,
+                    Err(e) => {
+                        guard.rollback().await;
+                        return Err(#begin_failed);
+                    }
+                };
+            }
+        })
+        .collect();
 
-    let db_commit = db_types.types.iter().map(|db_type| {
-        quote! {
-            cx = #db_type::commit_transaction_in_context(cx).await.expect(&format!("Failed to commit transaction for {}", stringify!(#db_type)));
-        }
-    });
+    // Only the transactions created by this function are committed or rolled
+    // back here, the ones joined from the caller are left to the caller
+    let two_phase_commit = db_types.two_phase_commit();
 
-    let db_rollback = db_types.types.iter().map(|db_type| {
+    // The `cx` of the function is moved into the guard, the body gets the one
+    // with the transactions instead, unless an argument is named `cx`
+    let cx_arg = fn_args.iter().any(|arg| match arg {
+        FnArg::Typed(arg) => matches!(arg.pat.as_ref(), Pat::Ident(pat) if pat.ident == "cx"),
+        FnArg::Receiver(_) => false,
+    });
+    let body = if cx_arg {
+        quote! { async move #fn_body }
+    } else {
         quote! {
-            cx = #db_type::rollback_transaction_in_context(cx).await.expect(&format!("Failed to rollback transaction for {}", stringify!(#db_type)));
+            async move {
+                #[allow(unused_variables)]
+                let cx = Context::current();
+                #fn_body
+            }
         }
-    });
+    };
 
-    let expanded = quote! {
-        #fn_vis async fn #fn_name #impl_generics(#fn_args) #where_clause -> #return_type {
-            let mut cx = Context::current();
-            #(#db_setup)*
-            cx = cx #(#cx_setup)*;
+    let expanded = match &db_types.retry {
+        None => {
+            quote! {
+                #fn_vis async fn #fn_name #impl_generics(#fn_args) #where_clause -> #return_type {
+                    let cx = Context::current();
+                    #(#db_check)*
 
-            let result = async move {
-                #fn_body
-            }.with_context(cx.clone()).await;
+                    let mut guard = common::database::TransactionGuard::new(cx);
+                    #(#db_setup)*
+                    let result = guard.run(#body).await;
 
-            match result {
-                Ok(value) => {
-                    // Commit the transactions
-                    #(#db_commit)*
-                    Ok(value)
+                    match result {
+                        Ok(value) => {
+                            // Commit the transactions
+                            #two_phase_commit
+                            match guard.commit(commit).await {
+                                Ok(()) => Ok(value),
+                                Err(failed) => Err(failed.error),
+                            }
+                        }
+                        Err(e) => {
+                            // A failed rollback ends the transaction all the same, so the
+                            // error of the body is returned, it tells more about what went wrong
+                            guard.rollback().await;
+                            Err(e)
+                        }
+                    }
                 }
-                Err(e) => {
-                    // Rollback the transactions
-                    #(#db_rollback)*
-                    Err(e)
+            }
+        }
+        Some(retry) => {
+            let clone_args = match clone_args(fn_args) {
+                Ok(clone_args) => clone_args,
+                Err(error) => return error.to_compile_error().into(),
+            };
+            // The `mut` of an argument only applies to its copies
+            let mut fn_args = fn_args.clone();
+            for arg in fn_args.iter_mut() {
+                if let FnArg::Typed(arg) = arg {
+                    if let Pat::Ident(pat) = arg.pat.as_mut() {
+                        pat.mutability = None;
+                    }
+                }
+            }
+            let scope_vars: Vec<_> = (0..db_types.types.len())
+                .map(|i| format_ident!("scope_{}", i))
+                .collect();
+
+            quote! {
+                #fn_vis async fn #fn_name #impl_generics(#fn_args) #where_clause -> #return_type {
+                    // Defined first, so the body can not see the variables below
+                    let body = || {
+                        #(#clone_args)*
+                        #body
+                    };
+                    let policy = #retry;
+
+                    let mut attempt: u32 = 0;
+                    loop {
+                        attempt += 1;
+                        let cx = Context::current();
+                        #(#db_check)*
+
+                        let mut guard = common::database::TransactionGuard::new(cx);
+                        #(#db_setup)*
+
+                        // Retrying inside a transaction joined from the caller can not
+                        // help, the caller has to retry the whole transaction instead.
+                        // Whether to retry is decided by the last error of the databases
+                        // recorded in the guard, not by the error type of the function
+                        let can_retry = [#(#scope_vars),*]
+                            .iter()
+                            .all(|scope| *scope == common::database::TransactionScope::Transaction);
+
+                        let result = guard.run(body()).await;
+
+                        match result {
+                            Ok(value) => {
+                                // Commit the transactions. A failed commit is retried as well,
+                                // serialization failures of SERIALIZABLE transactions are often
+                                // only reported there, but not after some of the databases
+                                // committed, which would apply the body twice to them
+                                #two_phase_commit
+                                match guard.commit(commit).await {
+                                    Ok(()) => return Ok(value),
+                                    Err(failed)
+                                        if can_retry
+                                            && !failed.in_doubt
+                                            && policy.should_retry(attempt, &**guard.retry_tracker()) =>
+                                    {
+                                        policy.wait(attempt).await;
+                                        continue;
+                                    }
+                                    Err(failed) => return Err(failed.error),
+                                }
+                            }
+                            Err(e) => {
+                                // Rollback the transactions
+                                guard.rollback().await;
+                                if can_retry && policy.should_retry(attempt, &**guard.retry_tracker()) {
+                                    policy.wait(attempt).await;
+                                    continue;
+                                }
+                                return Err(e);
+                            }
+                        }
+                    }
                 }
             }
         }
@@ -89,3 +441,31 @@ pub fn transactional(attr: TokenStream, item: TokenStream) -> TokenStream {
 
     TokenStream::from(expanded)
 }
+
+/// Every attempt of a retried function runs on its own copy of the arguments
+fn clone_args(args: &Punctuated<FnArg, Token![,]>) -> syn::Result<Vec<proc_macro2::TokenStream>> {
+    args.iter()
+        .filter_map(|arg| match arg {
+            FnArg::Receiver(receiver) if receiver.reference.is_none() => {
+                Some(Err(syn::Error::new_spanned(
+                    receiver,
+                    "`retry` does not support methods taking `self` by value",
+                )))
+            }
+            FnArg::Receiver(_) => None,
+            FnArg::Typed(arg) => match arg.pat.as_ref() {
+                Pat::Ident(pat) if pat.by_ref.is_none() && pat.subpat.is_none() => {
+                    let mutability = &pat.mutability;
+                    let ident = &pat.ident;
+                    Some(Ok(quote! {
+                        let #mutability #ident = ::std::clone::Clone::clone(&#ident);
+                    }))
+                }
+                pat => Some(Err(syn::Error::new_spanned(
+                    pat,
+                    "`retry` requires every argument to be a plain identifier",
+                ))),
+            },
+        })
+        .collect()
+}
@@ -0,0 +1,1255 @@
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use common::context::Context;
+use common::database::{
+    AccessMode, Database, DatabaseMarker, DefaultDatabase, IsolationLevel, Propagation,
+    RetryTracker, RetryableError, TransactionError, TransactionOptions,
+};
+use common::with_context::FutureExt;
+use macros::transactional;
+
+type Log = Arc<Mutex<Vec<String>>>;
+/// The `older_than` of every listing of prepared transactions
+type Cutoffs = Arc<Mutex<Vec<Duration>>>;
+
+/// An in-memory database which records every transaction operation
+#[derive(Default)]
+struct FakeDb {
+    log: Log,
+    next_id: AtomicUsize,
+    two_phase: bool,
+    prepared: Log,
+    cutoffs: Cutoffs,
+}
+
+struct FakeTxn {
+    id: String,
+    options: TransactionOptions,
+    children: AtomicUsize,
+    fail_commit: AtomicBool,
+    fail_rollback: AtomicBool,
+    log: Log,
+    prepared: Log,
+}
+
+impl FakeTxn {
+    fn new(id: String, options: TransactionOptions, log: &Log, prepared: &Log) -> Self {
+        log.lock().unwrap().push(format!("begin {id}"));
+        FakeTxn {
+            id,
+            options,
+            children: AtomicUsize::new(0),
+            fail_commit: AtomicBool::new(false),
+            fail_rollback: AtomicBool::new(false),
+            log: log.clone(),
+            prepared: prepared.clone(),
+        }
+    }
+
+    fn record(&self, operation: &str) -> Result<(), FakeError> {
+        let entry = format!("{operation} {}", self.id);
+        self.log.lock().unwrap().push(entry);
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum FakeError {
+    Transaction(TransactionError),
+    Failed,
+    Conflict,
+}
+
+impl std::fmt::Display for FakeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FakeError::Transaction(error) => error.fmt(f),
+            FakeError::Failed => f.write_str("failed"),
+            FakeError::Conflict => f.write_str("conflict"),
+        }
+    }
+}
+
+impl RetryableError for FakeError {
+    fn is_retryable(&self) -> bool {
+        *self == FakeError::Conflict
+    }
+}
+
+impl From<TransactionError> for FakeError {
+    fn from(error: TransactionError) -> Self {
+        FakeError::Transaction(error)
+    }
+}
+
+#[async_trait::async_trait]
+impl Database for FakeDb {
+    type DatabaseConnection = ();
+    type DatabaseTransaction = FakeTxn;
+    type DatabaseError = FakeError;
+
+    async fn create_transaction_with(
+        &self,
+        options: TransactionOptions,
+    ) -> Result<FakeTxn, FakeError> {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst) + 1;
+        Ok(FakeTxn::new(
+            id.to_string(),
+            options,
+            &self.log,
+            &self.prepared,
+        ))
+    }
+
+    async fn create_savepoint(transaction: &FakeTxn) -> Result<FakeTxn, FakeError> {
+        let child = transaction.children.fetch_add(1, Ordering::SeqCst) + 1;
+        let id = format!("{}.{}", transaction.id, child);
+        Ok(FakeTxn::new(
+            id,
+            transaction.options,
+            &transaction.log,
+            &transaction.prepared,
+        ))
+    }
+
+    async fn rollback_to_savepoint(savepoint: FakeTxn) -> Result<(), FakeError> {
+        savepoint.record("rollback to")
+    }
+
+    async fn release_savepoint(savepoint: FakeTxn) -> Result<(), FakeError> {
+        savepoint.record("release")
+    }
+
+    async fn rollback_transaction(transaction: FakeTxn) -> Result<(), FakeError> {
+        if transaction.fail_rollback.load(Ordering::SeqCst) {
+            transaction.record("rollback failed")?;
+            return Err(FakeError::Failed);
+        }
+        transaction.record("rollback")
+    }
+
+    async fn commit_transaction(transaction: FakeTxn) -> Result<(), FakeError> {
+        if transaction.fail_commit.load(Ordering::SeqCst) {
+            transaction.record("commit failed")?;
+            return Err(FakeError::Failed);
+        }
+        transaction.record("commit")
+    }
+
+    fn two_phase_name(&self) -> Option<&str> {
+        self.two_phase.then_some("fake")
+    }
+
+    async fn has_writes(transaction: &FakeTxn) -> Result<bool, FakeError> {
+        Ok(transaction.options.access_mode != Some(AccessMode::ReadOnly))
+    }
+
+    async fn prepare_transaction(transaction: FakeTxn, id: &str) -> Result<(), FakeError> {
+        transaction.prepared.lock().unwrap().push(id.to_string());
+        transaction.record("prepare")
+    }
+
+    async fn commit_prepared(&self, id: &str) -> Result<(), FakeError> {
+        self.prepared
+            .lock()
+            .unwrap()
+            .retain(|prepared| prepared != id);
+        self.log.lock().unwrap().push("commit prepared".to_string());
+        Ok(())
+    }
+
+    async fn rollback_prepared(&self, id: &str) -> Result<(), FakeError> {
+        self.prepared
+            .lock()
+            .unwrap()
+            .retain(|prepared| prepared != id);
+        self.log
+            .lock()
+            .unwrap()
+            .push("rollback prepared".to_string());
+        Ok(())
+    }
+
+    async fn prepared_transactions(&self, older_than: Duration) -> Result<Vec<String>, FakeError> {
+        self.cutoffs.lock().unwrap().push(older_than);
+        Ok(self.prepared.lock().unwrap().clone())
+    }
+}
+
+/// A second database for two-phase commit, which writes into the log of `FakeDb`
+#[derive(Default)]
+struct Ledger<M = DefaultDatabase> {
+    log: Log,
+    prepared: Log,
+    fail_begin: bool,
+    stall_begin: bool,
+    slow_commit: bool,
+    fail_prepare: bool,
+    fail_commit_prepared: bool,
+    fail_rollback_prepared: bool,
+    cutoffs: Cutoffs,
+    _marker: PhantomData<fn() -> M>,
+}
+
+struct LedgerTxn<M> {
+    log: Log,
+    prepared: Log,
+    slow_commit: bool,
+    fail_prepare: bool,
+    _marker: PhantomData<fn() -> M>,
+}
+
+/// A ledger named by a marker is named after the marker instead
+fn name<M>() -> &'static str {
+    match std::any::type_name::<M>().rsplit("::").next() {
+        Some("DefaultDatabase") | None => "ledger",
+        Some(name) => name,
+    }
+}
+
+fn record<M>(log: &Log, operation: &str) {
+    log.lock()
+        .unwrap()
+        .push(format!("{operation} {}", name::<M>()));
+}
+
+#[derive(Default)]
+struct Archive;
+
+impl DatabaseMarker for Archive {}
+
+#[async_trait::async_trait]
+impl<M: Send + Sync + 'static> Database for Ledger<M> {
+    type DatabaseConnection = ();
+    type DatabaseTransaction = LedgerTxn<M>;
+    type DatabaseError = FakeError;
+
+    async fn create_transaction_with(
+        &self,
+        _: TransactionOptions,
+    ) -> Result<LedgerTxn<M>, FakeError> {
+        if self.fail_begin {
+            record::<M>(&self.log, "begin failed");
+            return Err(FakeError::Failed);
+        }
+        if self.stall_begin {
+            std::future::pending::<()>().await;
+        }
+        record::<M>(&self.log, "begin");
+        Ok(LedgerTxn {
+            log: self.log.clone(),
+            prepared: self.prepared.clone(),
+            slow_commit: self.slow_commit,
+            fail_prepare: self.fail_prepare,
+            _marker: PhantomData,
+        })
+    }
+
+    async fn create_savepoint(_: &LedgerTxn<M>) -> Result<LedgerTxn<M>, FakeError> {
+        Err(FakeError::Failed)
+    }
+
+    async fn rollback_to_savepoint(_: LedgerTxn<M>) -> Result<(), FakeError> {
+        Err(FakeError::Failed)
+    }
+
+    async fn release_savepoint(_: LedgerTxn<M>) -> Result<(), FakeError> {
+        Err(FakeError::Failed)
+    }
+
+    async fn rollback_transaction(transaction: LedgerTxn<M>) -> Result<(), FakeError> {
+        record::<M>(&transaction.log, "rollback");
+        Ok(())
+    }
+
+    async fn commit_transaction(transaction: LedgerTxn<M>) -> Result<(), FakeError> {
+        if transaction.slow_commit {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        record::<M>(&transaction.log, "commit");
+        Ok(())
+    }
+
+    fn two_phase_name(&self) -> Option<&str> {
+        Some(name::<M>())
+    }
+
+    async fn prepare_transaction(transaction: LedgerTxn<M>, id: &str) -> Result<(), FakeError> {
+        if transaction.fail_prepare {
+            record::<M>(&transaction.log, "prepare failed");
+            return Err(FakeError::Conflict);
+        }
+        record::<M>(&transaction.log, "prepare");
+        transaction.prepared.lock().unwrap().push(id.to_string());
+        Ok(())
+    }
+
+    async fn commit_prepared(&self, id: &str) -> Result<(), FakeError> {
+        if self.fail_commit_prepared {
+            record::<M>(&self.log, "commit prepared failed");
+            return Err(FakeError::Failed);
+        }
+        self.prepared
+            .lock()
+            .unwrap()
+            .retain(|prepared| prepared != id);
+        record::<M>(&self.log, "commit prepared");
+        Ok(())
+    }
+
+    async fn rollback_prepared(&self, id: &str) -> Result<(), FakeError> {
+        if self.fail_rollback_prepared {
+            record::<M>(&self.log, "rollback prepared failed");
+            return Err(FakeError::Failed);
+        }
+        self.prepared
+            .lock()
+            .unwrap()
+            .retain(|prepared| prepared != id);
+        record::<M>(&self.log, "rollback prepared");
+        Ok(())
+    }
+
+    async fn prepared_transactions(&self, older_than: Duration) -> Result<Vec<String>, FakeError> {
+        self.cutoffs.lock().unwrap().push(older_than);
+        Ok(self.prepared.lock().unwrap().clone())
+    }
+}
+
+fn current_txn() -> Option<String> {
+    Context::map_current(|cx| cx.get::<FakeTxn>().map(|txn| txn.id.clone()))
+}
+
+async fn run<F: std::future::Future>(future: F) -> (F::Output, Vec<String>) {
+    let db = FakeDb::default();
+    let log = db.log.clone();
+    let output = future.with_context(Context::new().with_value(db)).await;
+    let log = log.lock().unwrap().clone();
+    (output, log)
+}
+
+#[transactional(FakeDb)]
+async fn required(fail: bool) -> Result<Option<String>, FakeError> {
+    if fail {
+        return Err(FakeError::Failed);
+    }
+    Ok(current_txn())
+}
+
+#[transactional(FakeDb, propagation = "requires_new")]
+async fn requires_new() -> Result<Option<String>, FakeError> {
+    Ok(current_txn())
+}
+
+#[transactional(FakeDb, propagation = "nested")]
+async fn nested(fail: bool) -> Result<Option<String>, FakeError> {
+    required(fail).await
+}
+
+#[transactional(FakeDb, propagation = "supports")]
+async fn supports() -> Result<Option<String>, FakeError> {
+    Ok(current_txn())
+}
+
+#[transactional(FakeDb, isolation = "serializable", read_only, deferrable)]
+async fn serializable_read_only() -> Result<Option<TransactionOptions>, FakeError> {
+    Ok(Context::map_current(|cx| {
+        cx.get::<FakeTxn>().map(|txn| txn.options)
+    }))
+}
+
+/// `cx` in the body is the context with the transaction
+#[transactional(FakeDb)]
+async fn txn_of_cx() -> Result<Option<String>, FakeError> {
+    Ok(cx.get::<FakeTxn>().map(|txn| txn.id.clone()))
+}
+
+#[transactional(FakeDb, retry = 1, backoff = "none")]
+async fn retried_txn_of_cx() -> Result<Option<String>, FakeError> {
+    Ok(cx.get::<FakeTxn>().map(|txn| txn.id.clone()))
+}
+
+/// A query which fails with a conflict until `conflicts` reaches zero, the
+/// error is recorded like `ContextConnection` does
+fn query(conflicts: &AtomicUsize) -> Result<(), FakeError> {
+    let remaining = conflicts.load(Ordering::SeqCst);
+    if remaining == 0 {
+        return Ok(());
+    }
+    conflicts.store(remaining - 1, Ordering::SeqCst);
+    let error = FakeError::Conflict;
+    Context::map_current(|cx| RetryTracker::record_in(cx, &error));
+    Err(error)
+}
+
+#[transactional(FakeDb, retry = 2, backoff = "none")]
+async fn conflicting(conflicts: Arc<AtomicUsize>) -> Result<Option<String>, FakeError> {
+    query(&conflicts)?;
+    Ok(current_txn())
+}
+
+/// The error type of the function does not implement `RetryableError`
+#[transactional(FakeDb, retry = 2, backoff = "none", map_err = describe)]
+async fn conflicting_described(conflicts: Arc<AtomicUsize>) -> Result<Option<String>, String> {
+    query(&conflicts).map_err(describe)?;
+    Ok(current_txn())
+}
+
+#[transactional(FakeDb, propagation = "requires_new")]
+async fn inner_conflicting(conflicts: Arc<AtomicUsize>) -> Result<(), FakeError> {
+    query(&conflicts)
+}
+
+/// The inner function runs in its own transaction, its conflict retries the outer one
+#[transactional(FakeDb, retry = 1, backoff = "none", map_err = describe)]
+async fn outer_conflicting(conflicts: Arc<AtomicUsize>) -> Result<(), String> {
+    inner_conflicting(conflicts).await.map_err(describe)
+}
+
+#[transactional(FakeDb, retry = 2, backoff = "none")]
+async fn failing_without_query() -> Result<(), FakeError> {
+    Err(FakeError::Conflict)
+}
+
+/// Registers hooks which write into the log of the database
+fn record_hooks(name: &'static str) {
+    Context::map_current(|cx| {
+        let log = cx.get::<FakeDb>().unwrap().log.clone();
+        let rollback_log = log.clone();
+        FakeDb::on_commit(cx, move || async move {
+            log.lock().unwrap().push(format!("{name} committed"));
+        })
+        .unwrap();
+        FakeDb::on_rollback(cx, move || async move {
+            rollback_log
+                .lock()
+                .unwrap()
+                .push(format!("{name} rolled back"));
+        })
+        .unwrap();
+    })
+}
+
+#[transactional(FakeDb, propagation = "nested")]
+async fn hooked_step(name: &'static str, fail: bool) -> Result<(), FakeError> {
+    record_hooks(name);
+    if fail {
+        return Err(FakeError::Failed);
+    }
+    Ok(())
+}
+
+#[transactional(FakeDb)]
+async fn hooked(fail: bool) -> Result<(), FakeError> {
+    record_hooks("outer");
+    hooked_step("kept", false).await?;
+    let _ = hooked_step("discarded", true).await;
+    if fail {
+        return Err(FakeError::Failed);
+    }
+    Ok(())
+}
+
+#[transactional(FakeDb)]
+async fn failing_commit() -> Result<u32, FakeError> {
+    Context::map_current(|cx| {
+        let txn = cx.get::<FakeTxn>().unwrap();
+        txn.fail_commit.store(true, Ordering::SeqCst);
+    });
+    Ok(1)
+}
+
+fn describe(error: FakeError) -> String {
+    format!("{error:?}")
+}
+
+#[transactional(FakeDb, map_err = describe)]
+async fn described() -> Result<(), String> {
+    Ok(())
+}
+
+#[transactional(FakeDb, propagation = "mandatory", map_err = |e: FakeError| e.to_string())]
+async fn mandatory() -> Result<(), String> {
+    Ok(())
+}
+
+#[transactional(FakeDb)]
+async fn panicking() -> Result<(), FakeError> {
+    record_hooks("panicking");
+    panic!("boom");
+}
+
+#[transactional(FakeDb)]
+async fn stuck() -> Result<(), FakeError> {
+    record_hooks("stuck");
+    std::future::pending().await
+}
+
+struct Counter;
+
+impl Counter {
+    #[transactional(FakeDb, retry = 1, backoff = "fixed")]
+    async fn add(&self, mut value: usize, step: usize) -> Result<usize, FakeError> {
+        value += step;
+        Ok(value)
+    }
+}
+
+#[transactional(FakeDb, Ledger)]
+async fn transfer() -> Result<(), FakeError> {
+    record_hooks("transfer");
+    Ok(())
+}
+
+#[transactional(FakeDb, Ledger)]
+async fn failing_transfer() -> Result<(), FakeError> {
+    record_hooks("transfer");
+    Context::map_current(|cx| {
+        let txn = cx.get::<FakeTxn>().unwrap();
+        txn.fail_rollback.store(true, Ordering::SeqCst);
+    });
+    Err(FakeError::Conflict)
+}
+
+#[transactional(FakeDb, Ledger, read_only)]
+async fn read_only_transfer() -> Result<(), FakeError> {
+    Ok(())
+}
+
+#[transactional(FakeDb, Ledger, retry = 1, backoff = "none")]
+async fn retried_transfer() -> Result<(), FakeError> {
+    Ok(())
+}
+
+#[transactional(FakeDb, Ledger, retry = 1, backoff = "none", map_err = describe)]
+async fn retried_described_transfer() -> Result<(), String> {
+    Ok(())
+}
+
+/// Runs `future` with a `FakeDb` and `ledger`, both writing into the same log
+async fn run_with_ledger<F: std::future::Future>(
+    two_phase: bool,
+    ledger: Ledger,
+    future: F,
+) -> (F::Output, Vec<String>) {
+    let db = FakeDb {
+        two_phase,
+        ..FakeDb::default()
+    };
+    let log = db.log.clone();
+    let ledger = Ledger {
+        log: log.clone(),
+        ..ledger
+    };
+    let cx = Context::new().with_value(db).with_value(ledger);
+    let output = future.with_context(cx).await;
+    let log = log.lock().unwrap().clone();
+    (output, log)
+}
+
+#[transactional(Ledger, Ledger<Archive>)]
+async fn archived() -> Result<usize, FakeError> {
+    Ok(Context::map_current(|cx| {
+        usize::from(cx.get::<LedgerTxn<DefaultDatabase>>().is_some())
+            + usize::from(cx.get::<LedgerTxn<Archive>>().is_some())
+    }))
+}
+
+#[transactional(FakeDb, Ledger, Ledger<Archive>, retry = 1, backoff = "none")]
+async fn archived_transfer() -> Result<(), FakeError> {
+    Ok(())
+}
+
+#[transactional(FakeDb)]
+async fn outer() -> Result<Vec<Option<String>>, FakeError> {
+    let mut seen = vec![current_txn()];
+    seen.push(required(false).await?);
+    seen.push(requires_new().await?);
+    seen.push(supports().await?);
+    seen.push(nested(false).await?);
+    let _ = nested(true).await;
+    seen.push(current_txn());
+    Ok(seen)
+}
+
+#[tokio::test]
+async fn required_creates_and_commits() {
+    let (result, log) = run(required(false)).await;
+    assert_eq!(result, Ok(Some("1".to_string())));
+    assert_eq!(log, ["begin 1", "commit 1"]);
+
+    let (result, log) = run(required(true)).await;
+    assert_eq!(result, Err(FakeError::Failed));
+    assert_eq!(log, ["begin 1", "rollback 1"]);
+}
+
+#[tokio::test]
+async fn options_are_used_for_new_transactions() {
+    let (result, _) = run(serializable_read_only()).await;
+    let expected = TransactionOptions::new()
+        .isolation(IsolationLevel::Serializable)
+        .read_only()
+        .deferrable();
+    assert_eq!(result, Ok(Some(expected)));
+}
+
+#[tokio::test]
+async fn retries_conflicts() {
+    let (result, log) = run(conflicting(Arc::new(AtomicUsize::new(2)))).await;
+    assert_eq!(result, Ok(Some("3".to_string())));
+    assert_eq!(
+        log,
+        [
+            "begin 1",
+            "rollback 1",
+            "begin 2",
+            "rollback 2",
+            "begin 3",
+            "commit 3"
+        ]
+    );
+
+    let (result, log) = run(conflicting(Arc::new(AtomicUsize::new(3)))).await;
+    assert_eq!(result, Err(FakeError::Conflict));
+    assert_eq!(log.len(), 6);
+}
+
+#[tokio::test]
+async fn database_errors_decide_the_retry() {
+    let (result, log) = run(conflicting_described(Arc::new(AtomicUsize::new(2)))).await;
+    assert_eq!(result, Ok(Some("3".to_string())));
+    assert_eq!(log.len(), 6);
+
+    let (result, log) = run(conflicting_described(Arc::new(AtomicUsize::new(3)))).await;
+    assert_eq!(result, Err("Conflict".to_string()));
+    assert_eq!(log.len(), 6);
+
+    let (result, log) = run(outer_conflicting(Arc::new(AtomicUsize::new(1)))).await;
+    assert_eq!(result, Ok(()));
+    assert_eq!(
+        log,
+        [
+            "begin 1",
+            "begin 2",
+            "rollback 2",
+            "rollback 1",
+            "begin 3",
+            "begin 4",
+            "commit 4",
+            "commit 3"
+        ]
+    );
+
+    // No database reported the conflict, so it is not retried
+    let (result, log) = run(failing_without_query()).await;
+    assert_eq!(result, Err(FakeError::Conflict));
+    assert_eq!(log, ["begin 1", "rollback 1"]);
+}
+
+#[tokio::test]
+async fn joined_transactions_are_not_retried() {
+    let conflicts = Arc::new(AtomicUsize::new(1));
+    let (result, log) = run(async {
+        let txn = FakeDb::default().create_transaction().await.unwrap();
+        conflicting(conflicts.clone())
+            .with_context(Context::current().with_value(txn))
+            .await
+    })
+    .await;
+    assert_eq!(result, Err(FakeError::Conflict));
+    assert!(log.is_empty());
+    assert_eq!(conflicts.load(Ordering::SeqCst), 0);
+}
+
+#[tokio::test]
+async fn hooks_run_after_the_outcome() {
+    let (result, log) = run(hooked(false)).await;
+    assert_eq!(result, Ok(()));
+    assert_eq!(
+        log,
+        [
+            "begin 1",
+            "begin 1.1",
+            "release 1.1",
+            "begin 1.2",
+            "rollback to 1.2",
+            "discarded rolled back",
+            "commit 1",
+            "outer committed",
+            "kept committed",
+        ]
+    );
+
+    let (result, log) = run(hooked(true)).await;
+    assert_eq!(result, Err(FakeError::Failed));
+    assert_eq!(
+        log[log.len() - 3..],
+        ["rollback 1", "outer rolled back", "kept rolled back"]
+    );
+}
+
+#[tokio::test]
+async fn errors_are_returned() {
+    let (result, log) = run(failing_commit()).await;
+    assert_eq!(result, Err(FakeError::Failed));
+    assert_eq!(log, ["begin 1", "commit failed 1"]);
+
+    assert_eq!(
+        described().await,
+        Err(r#"Transaction(DatabaseNotFound("FakeDb"))"#.to_string())
+    );
+    let (result, _) = run(described()).await;
+    assert_eq!(result, Ok(()));
+
+    let (result, log) = run(mandatory()).await;
+    assert_eq!(
+        result,
+        Err("no existing transaction found in the context".to_string())
+    );
+    assert!(log.is_empty());
+}
+
+#[tokio::test]
+async fn panic_rolls_back() {
+    let db = FakeDb::default();
+    let log = db.log.clone();
+    let handle = tokio::spawn(panicking().with_context(Context::new().with_value(db)));
+
+    assert!(handle.await.unwrap_err().is_panic());
+    assert_eq!(
+        *log.lock().unwrap(),
+        ["begin 1", "rollback 1", "panicking rolled back"]
+    );
+}
+
+#[tokio::test]
+async fn cancellation_rolls_back() {
+    let db = FakeDb::default();
+    let log = db.log.clone();
+    let cx = Context::new().with_value(db);
+    let timeout = tokio::time::timeout(Duration::from_millis(10), stuck().with_context(cx));
+    assert!(timeout.await.is_err());
+
+    // The rollback of a dropped future runs in a spawned task
+    for _ in 0..10 {
+        if log.lock().unwrap().len() == 3 {
+            break;
+        }
+        tokio::task::yield_now().await;
+    }
+    assert_eq!(
+        *log.lock().unwrap(),
+        ["begin 1", "rollback 1", "stuck rolled back"]
+    );
+}
+
+/// Waits for the rollback or commit which a dropped future left to a spawned task
+async fn wait_for(log: &Log, len: usize) -> Vec<String> {
+    for _ in 0..100 {
+        if log.lock().unwrap().len() >= len {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+    log.lock().unwrap().clone()
+}
+
+#[tokio::test]
+async fn cancellation_while_beginning_rolls_back() {
+    let db = FakeDb::default();
+    let log = db.log.clone();
+    let ledger: Ledger = Ledger {
+        log: log.clone(),
+        stall_begin: true,
+        ..Ledger::default()
+    };
+    let cx = Context::new().with_value(db).with_value(ledger);
+    let timeout = tokio::time::timeout(Duration::from_millis(10), transfer().with_context(cx));
+    assert!(timeout.await.is_err());
+
+    assert_eq!(wait_for(&log, 2).await, ["begin 1", "rollback 1"]);
+}
+
+#[tokio::test]
+async fn cancellation_while_committing_finishes_the_commit() {
+    let ledger = Ledger {
+        slow_commit: true,
+        ..Ledger::default()
+    };
+    let (result, log) = run_with_ledger(false, ledger, async {
+        let log = Context::map_current(|cx| cx.get::<FakeDb>().unwrap().log.clone());
+        let timeout = tokio::time::timeout(Duration::from_millis(10), transfer());
+        assert!(timeout.await.is_err());
+        wait_for(&log, 5).await
+    })
+    .await;
+    assert_eq!(
+        result,
+        [
+            "begin 1",
+            "begin ledger",
+            "commit 1",
+            "transfer committed",
+            "commit ledger",
+        ]
+    );
+    assert_eq!(log, result);
+}
+
+#[test]
+fn hooks_require_a_transaction() {
+    let error = FakeDb::on_commit(&Context::new(), || async {}).err();
+    assert_eq!(
+        error,
+        Some(FakeError::Transaction(
+            TransactionError::TransactionRequired
+        ))
+    );
+}
+
+#[tokio::test]
+async fn hooks_require_a_transaction_with_hooks() {
+    let db = FakeDb::default();
+    let txn = db.create_transaction().await.unwrap();
+    let cx = Context::new().with_value(txn);
+    let savepoint = FakeDb::create_savepoint_in_context(cx).await.unwrap();
+
+    // Nothing would run the hooks when the transaction is committed
+    let error = FakeDb::on_commit(&savepoint, || async {}).err();
+    assert_eq!(
+        error,
+        Some(FakeError::Transaction(TransactionError::HooksNotFound))
+    );
+}
+
+#[tokio::test]
+async fn retried_methods() {
+    let (result, _) = run(Counter.add(1, 2)).await;
+    assert_eq!(result, Ok(3));
+}
+
+#[test]
+fn backoff_delay() {
+    use common::database::Backoff;
+
+    let backoff = Backoff::exponential();
+    assert_eq!(backoff.delay(1), Duration::from_millis(10));
+    assert_eq!(backoff.delay(3), Duration::from_millis(40));
+    assert_eq!(backoff.delay(40), Duration::from_secs(1));
+    assert_eq!(Backoff::None.delay(2), Duration::ZERO);
+}
+
+#[tokio::test]
+async fn supports_without_transaction() {
+    let (result, log) = run(supports()).await;
+    assert_eq!(result, Ok(None));
+    assert!(log.is_empty());
+}
+
+#[tokio::test]
+async fn nested_calls_follow_propagation() {
+    let (result, log) = run(outer()).await;
+    let seen: Vec<_> = result.unwrap().into_iter().map(Option::unwrap).collect();
+    assert_eq!(seen, ["1", "1", "2", "1", "1.1", "1"]);
+    assert_eq!(
+        log,
+        [
+            "begin 1",
+            "begin 2",
+            "commit 2",
+            "begin 1.1",
+            "release 1.1",
+            "begin 1.2",
+            "rollback to 1.2",
+            "commit 1",
+        ]
+    );
+}
+
+#[tokio::test]
+async fn mandatory_and_never() {
+    let db = FakeDb::default();
+    let (cx, _) = db
+        .begin_in_context(
+            Context::new(),
+            Propagation::Never,
+            TransactionOptions::new(),
+        )
+        .await
+        .unwrap();
+    let error = db
+        .begin_in_context(cx, Propagation::Mandatory, TransactionOptions::new())
+        .await
+        .err();
+    assert_eq!(
+        error,
+        Some(FakeError::Transaction(
+            TransactionError::TransactionRequired
+        ))
+    );
+
+    let cx = db
+        .create_transaction_in_context(Context::new())
+        .await
+        .unwrap();
+    let error = db
+        .begin_in_context(cx, Propagation::Never, TransactionOptions::new())
+        .await
+        .err();
+    assert_eq!(
+        error,
+        Some(FakeError::Transaction(
+            TransactionError::TransactionNotAllowed
+        ))
+    );
+}
+
+#[tokio::test]
+async fn savepoint_in_context() {
+    let db = FakeDb::default();
+    let log = db.log.clone();
+    let cx = db
+        .create_transaction_in_context(Context::new())
+        .await
+        .unwrap();
+
+    let savepoint = FakeDb::create_savepoint_in_context(cx.clone())
+        .await
+        .unwrap();
+    assert_eq!(savepoint.get::<FakeTxn>().unwrap().id, "1.1");
+    let savepoint = FakeDb::rollback_to_savepoint_in_context(savepoint)
+        .await
+        .unwrap();
+    assert!(savepoint.get::<FakeTxn>().is_none());
+
+    let savepoint = FakeDb::create_savepoint_in_context(cx.clone())
+        .await
+        .unwrap();
+    FakeDb::release_savepoint_in_context(savepoint)
+        .await
+        .unwrap();
+    FakeDb::commit_transaction_in_context(cx).await.unwrap();
+
+    assert_eq!(
+        *log.lock().unwrap(),
+        [
+            "begin 1",
+            "begin 1.1",
+            "rollback to 1.1",
+            "begin 1.2",
+            "release 1.2",
+            "commit 1",
+        ]
+    );
+
+    let error = FakeDb::create_savepoint_in_context(Context::new())
+        .await
+        .err();
+    assert_eq!(
+        error,
+        Some(FakeError::Transaction(
+            TransactionError::TransactionRequired
+        ))
+    );
+}
+
+#[tokio::test]
+async fn shared_transactions_are_not_committed() {
+    let db = FakeDb::default();
+    let log = db.log.clone();
+    let cx = db
+        .create_transaction_in_context(Context::new())
+        .await
+        .unwrap();
+    let shared = cx.clone();
+    let rollback_log = log.clone();
+    FakeDb::on_rollback(&cx, move || async move {
+        rollback_log.lock().unwrap().push("rolled back".to_string());
+    })
+    .unwrap();
+
+    let error = FakeDb::commit_transaction_in_context(cx).await.err();
+    assert_eq!(
+        error,
+        Some(FakeError::Transaction(
+            TransactionError::TransactionStillInUse
+        ))
+    );
+    drop(shared);
+    assert_eq!(*log.lock().unwrap(), ["begin 1", "rolled back"]);
+}
+
+#[tokio::test]
+async fn multiple_databases_use_two_phase_commit() {
+    let (result, log) = run_with_ledger(true, Ledger::default(), transfer()).await;
+    assert_eq!(result, Ok(()));
+    assert_eq!(
+        log,
+        [
+            "begin 1",
+            "begin ledger",
+            "prepare 1",
+            "prepare ledger",
+            "commit prepared",
+            "transfer committed",
+            "commit prepared ledger",
+        ]
+    );
+
+    let (result, log) = run_with_ledger(false, Ledger::default(), transfer()).await;
+    assert_eq!(result, Ok(()));
+    assert_eq!(
+        log,
+        [
+            "begin 1",
+            "begin ledger",
+            "commit 1",
+            "transfer committed",
+            "commit ledger",
+        ]
+    );
+}
+
+#[tokio::test]
+async fn transactions_without_writes_are_not_prepared() {
+    // Only the ledger writes, so it is committed on its own
+    let ledger = Ledger::default();
+    let prepared = ledger.prepared.clone();
+    let (result, log) = run_with_ledger(true, ledger, read_only_transfer()).await;
+    assert_eq!(result, Ok(()));
+    assert_eq!(
+        log,
+        ["begin 1", "begin ledger", "commit 1", "commit ledger"]
+    );
+    assert!(prepared.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn failed_prepare_rolls_back() {
+    let ledger = Ledger {
+        fail_prepare: true,
+        ..Ledger::default()
+    };
+    let (result, log) = run_with_ledger(true, ledger, transfer()).await;
+    assert_eq!(result, Err(FakeError::Conflict));
+    assert_eq!(
+        log,
+        [
+            "begin 1",
+            "begin ledger",
+            "prepare 1",
+            "prepare failed ledger",
+            "rollback prepared",
+            "transfer rolled back",
+        ]
+    );
+
+    // Nothing was committed, so the conflict is retried
+    let ledger = Ledger {
+        fail_prepare: true,
+        ..Ledger::default()
+    };
+    let (result, log) = run_with_ledger(true, ledger, retried_transfer()).await;
+    assert_eq!(result, Err(FakeError::Conflict));
+    assert_eq!(
+        log.iter().filter(|entry| *entry == "begin ledger").count(),
+        2
+    );
+
+    // Also when the error of the function is not retryable itself
+    let ledger = Ledger {
+        fail_prepare: true,
+        ..Ledger::default()
+    };
+    let (result, log) = run_with_ledger(true, ledger, retried_described_transfer()).await;
+    assert_eq!(result, Err("Conflict".to_string()));
+    assert_eq!(
+        log.iter().filter(|entry| *entry == "begin ledger").count(),
+        2
+    );
+}
+
+#[tokio::test]
+async fn failed_rollback_rolls_back_the_others() {
+    let (result, log) = run_with_ledger(false, Ledger::default(), failing_transfer()).await;
+    assert_eq!(result, Err(FakeError::Conflict));
+    assert_eq!(
+        log,
+        [
+            "begin 1",
+            "begin ledger",
+            "rollback failed 1",
+            "transfer rolled back",
+            "rollback ledger",
+        ]
+    );
+}
+
+#[tokio::test]
+async fn failed_begin_rolls_back() {
+    let ledger = Ledger {
+        fail_begin: true,
+        ..Ledger::default()
+    };
+    let (result, log) = run_with_ledger(false, ledger, transfer()).await;
+    assert_eq!(result, Err(FakeError::Failed));
+    assert_eq!(log, ["begin 1", "begin failed ledger", "rollback 1"]);
+}
+
+#[transactional(Ledger, FakeDb)]
+async fn ledger_first() -> Result<(), FakeError> {
+    Ok(())
+}
+
+#[tokio::test]
+async fn recovery_follows_the_coordinator() {
+    use common::database::TwoPhaseRecovery;
+
+    let db_prepared = Log::default();
+    let ledger_prepared = Log::default();
+    let cx = || {
+        let db = FakeDb {
+            two_phase: true,
+            prepared: db_prepared.clone(),
+            ..FakeDb::default()
+        };
+        let ledger: Ledger = Ledger {
+            prepared: ledger_prepared.clone(),
+            fail_commit_prepared: true,
+            ..Ledger::default()
+        };
+        Context::new().with_value(db).with_value(ledger)
+    };
+
+    // Committed by the coordinator, but left prepared in the ledger
+    assert_eq!(transfer().with_context(cx()).await, Err(FakeError::Failed));
+    assert_eq!(ledger_prepared.lock().unwrap().len(), 1);
+    // The whole names of the coordinator and the participant
+    assert!(ledger_prepared.lock().unwrap()[0].ends_with("-fake-ledger"));
+    // Still prepared in both, the ledger coordinates and failed to commit
+    assert_eq!(
+        ledger_first().with_context(cx()).await,
+        Err(FakeError::Failed)
+    );
+    assert_eq!(db_prepared.lock().unwrap().len(), 1);
+    assert_eq!(ledger_prepared.lock().unwrap().len(), 2);
+    // Coordinated by a database which is not recovered here
+    let unrelated = "ctx2pc-00000000-0-00000000".to_string();
+    ledger_prepared.lock().unwrap().push(unrelated.clone());
+
+    let db = FakeDb {
+        two_phase: true,
+        prepared: db_prepared.clone(),
+        ..FakeDb::default()
+    };
+    let ledger: Ledger = Ledger {
+        log: db.log.clone(),
+        prepared: ledger_prepared.clone(),
+        cutoffs: db.cutoffs.clone(),
+        ..Ledger::default()
+    };
+    let mut recovery = TwoPhaseRecovery::new(Duration::from_secs(60));
+    recovery.scan(&db).await.unwrap();
+    recovery.scan(&ledger).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(5)).await;
+    recovery.resolve(&db).await.unwrap();
+    recovery.resolve(&ledger).await.unwrap();
+    recovery.finish(&db).await.unwrap();
+    recovery.finish(&ledger).await.unwrap();
+
+    assert_eq!(
+        *db.log.lock().unwrap(),
+        [
+            "rollback prepared",
+            "commit prepared ledger",
+            "rollback prepared ledger",
+        ]
+    );
+    assert_eq!(*ledger_prepared.lock().unwrap(), [unrelated]);
+    assert!(db_prepared.lock().unwrap().is_empty());
+
+    // The point in time decided in `new`, so the later listings look further back
+    let cutoffs = db.cutoffs.lock().unwrap().clone();
+    assert_eq!(cutoffs.len(), 4);
+    assert!(cutoffs[0] >= Duration::from_secs(60));
+    assert!(cutoffs.windows(2).all(|pair| pair[0] <= pair[1]));
+    assert!(cutoffs[2] >= cutoffs[1] + Duration::from_millis(5));
+}
+
+#[tokio::test]
+async fn databases_of_the_same_type_by_marker() {
+    let log = Log::default();
+    let cx = Context::new()
+        .with_value(Ledger::<DefaultDatabase> {
+            log: log.clone(),
+            ..Ledger::default()
+        })
+        .with_value(Ledger::<Archive> {
+            log: log.clone(),
+            ..Ledger::default()
+        });
+    let result = archived().with_context(cx).await;
+    assert_eq!(result, Ok(2));
+    assert_eq!(
+        *log.lock().unwrap(),
+        [
+            "begin ledger",
+            "begin Archive",
+            "prepare ledger",
+            "prepare Archive",
+            "commit prepared ledger",
+            "commit prepared Archive",
+        ]
+    );
+}
+
+#[tokio::test]
+async fn failed_rollback_of_a_prepared_transaction_is_not_retried() {
+    let db = FakeDb {
+        two_phase: true,
+        ..FakeDb::default()
+    };
+    let log = db.log.clone();
+    let prepared = db.prepared.clone();
+    let cx = Context::new()
+        .with_value(db)
+        .with_value(Ledger::<DefaultDatabase> {
+            log: log.clone(),
+            fail_rollback_prepared: true,
+            ..Ledger::default()
+        })
+        .with_value(Ledger::<Archive> {
+            log: log.clone(),
+            fail_prepare: true,
+            ..Ledger::default()
+        });
+
+    // The conflict of the archive would be retried, but the ledger and the
+    // coordinator are left prepared
+    let result = archived_transfer().with_context(cx).await;
+    assert_eq!(result, Err(FakeError::Conflict));
+    assert_eq!(
+        *log.lock().unwrap(),
+        [
+            "begin 1",
+            "begin ledger",
+            "begin Archive",
+            "prepare 1",
+            "prepare ledger",
+            "prepare failed Archive",
+            "rollback prepared failed ledger",
+        ]
+    );
+    assert_eq!(prepared.lock().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn cx_in_the_body_has_the_transaction() {
+    let (result, _) = run(txn_of_cx()).await;
+    assert_eq!(result, Ok(Some("1".to_string())));
+    let (result, _) = run(retried_txn_of_cx()).await;
+    assert_eq!(result, Ok(Some("1".to_string())));
+}
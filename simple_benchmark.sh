@@ -1,5 +1,9 @@
 #!/bin/bash
 
+# Start the example first, with either Context backend:
+#   cargo run --release -p example                        # thread_local backend
+#   cargo run --release -p example --features task-local  # tokio task_local backend
+
 # Set your gRPC service details
 PROTO_FILE="./example/api/protos/test.proto"
 SERVICE_METHOD="test.TestService.SaveMsg"